@@ -1,21 +1,321 @@
-use crate::tex::{Color, MathMode, TexString};
-use std::marker::PhantomData;
+use crate::tex::{self, Color, MathMode, TexString, Usepackage, ValidationIssue};
+use std::{
+    collections::HashSet,
+    marker::PhantomData,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
-const DEFAULT_IMPORTS: &'static str = r#"\usepackage{amsmath}
+const DEFAULT_IMPORTS: &str = r#"\usepackage{amsmath}
 \usepackage{amssymb}
 \usepackage{amsfonts}
-\usepackage{xcolor}
+\usepackage[dvipsnames]{xcolor}
 \usepackage{siunitx}
 \usepackage[utf8]{inputenc}
 "#;
 
+const PAGE_STYLE: &str = r#"\thispagestyle{empty}"#;
+
 /// Represents options for documents.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct DocumentOptions {
     /// Color to be applied to the document text.
     pub text_color: Color,
     /// Preamble to be put before the begin document.
     pub preamble: String,
+    /// Class passed to `\documentclass`, e.g. `article` or `standalone`.
+    pub document_class: String,
+    /// Options passed to `\documentclass`, e.g. `12pt`.
+    pub class_options: Vec<String>,
+    /// Font size in points, overriding any size in [`DocumentOptions::class_options`].
+    ///
+    /// `10`, `11` and `12` become the matching class option, anything else is set with
+    /// `\fontsize{}{}\selectfont`. This changes the size of the glyphs in the TeX output,
+    /// `scale` in the render options is applied afterwards on top of it.
+    pub font_size_pt: Option<u8>,
+    /// Pin `siunitx` to a major version's syntax through its rollback mechanism, regardless of
+    /// which version the TeX distribution ships.
+    ///
+    /// Rolling back to v2 needs siunitx v3 installed, pinning v3 fails on distributions that
+    /// only ship v2.
+    pub siunitx_compat: Option<SiunitxVersion>,
+    /// Load TikZ's `external` library so pictures are compiled once and reused while their code
+    /// is unchanged.
+    ///
+    /// Pictures are compiled with `latex` and `dvips`/`ps2eps` into
+    /// [`DocumentOptions::externalize_dir`] and need shell escape to be enabled, which the
+    /// native backend does when this is set. Shell escape is off while
+    /// [`DocumentOptions::sandbox`] is on, turn that off to externalize.
+    pub externalize: bool,
+    /// Where externalized pictures are cached, relative to the directory the document is
+    /// compiled in unless absolute. Defaults to `tikz-cache`. Reusing the compile directory
+    /// across renders is what makes the cache effective.
+    pub externalize_dir: Option<PathBuf>,
+    /// Page size and margins through the `geometry` package, for content too wide for the
+    /// `article` defaults. `None` leaves the page alone.
+    pub geometry: Option<Geometry>,
+    /// Macros as `(name, expansion)` pairs, each emitted as `\newcommand{\name}{expansion}`
+    /// at the end of the preamble. Names may be given with or without the backslash, see
+    /// [`DocumentBuilder::add_macro`] for a validating way to add them.
+    pub macros: Vec<(String, String)>,
+    /// Strip trailing whitespace, blank lines and `\par`s from the content, which would
+    /// otherwise add an empty paragraph below it and grow the bounding box. On by default,
+    /// turn off for byte-exact content.
+    pub normalize_raw: bool,
+    /// Refuse to render content or [`DocumentOptions::macros`] using
+    /// [`crate::tex::UNSAFE_PRIMITIVES`], for content from untrusted sources, and compile with
+    /// shell escape disabled. On by default. The preamble is trusted and not checked.
+    pub sandbox: bool,
+    /// Directory searched for `\input`, `\usepackage` and friends after the compile directory,
+    /// for shared style files. Relative paths are resolved against the working directory of
+    /// the process.
+    ///
+    /// The native backend adds it to `TEXINPUTS`, it does not stop TeX from reading other
+    /// files. Content still can't `\input` while [`DocumentOptions::sandbox`] is on, load the
+    /// files from the preamble instead.
+    pub resource_dir: Option<PathBuf>,
+    /// Wrap the content at this width in centimetres with a `varwidth` box, so long raw
+    /// paragraphs render as a column. Narrower content keeps its natural width.
+    pub max_width_cm: Option<f32>,
+}
+
+/// Options for the `geometry` package, see [`DocumentOptions::geometry`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Geometry {
+    /// Named paper size, e.g. `a4paper` or `a3paper`.
+    pub paper: Option<String>,
+    /// Paper width and height with units, e.g. `("40cm", "20cm")`, overriding
+    /// [`Geometry::paper`].
+    pub paper_size: Option<(String, String)>,
+    /// Margin on all sides with units, e.g. `1cm`.
+    pub margin: Option<String>,
+    /// Rotate the paper by 90 degrees.
+    pub landscape: bool,
+}
+
+impl Geometry {
+    /// The `\usepackage[...]{geometry}` line.
+    pub fn usepackage(&self) -> String {
+        let mut options = Vec::new();
+        match (&self.paper_size, &self.paper) {
+            (Some((width, height)), _) => {
+                options.push(format!("paperwidth={}", width));
+                options.push(format!("paperheight={}", height));
+            }
+            (None, Some(paper)) => options.push(paper.clone()),
+            (None, None) => {}
+        }
+        if let Some(margin) = &self.margin {
+            options.push(format!("margin={}", margin));
+        }
+        if self.landscape {
+            options.push("landscape".to_string());
+        }
+
+        Usepackage::new_with_arbitrary("geometry".to_string(), options).to_tex()
+    }
+}
+
+/// Major version of `siunitx`, see [`DocumentOptions::siunitx_compat`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SiunitxVersion {
+    /// `\si`/`\SI` as in siunitx v2.
+    V2,
+    /// `\unit`/`\qty` as in siunitx v3.
+    V3,
+}
+
+impl SiunitxVersion {
+    fn usepackage(&self) -> &'static str {
+        match self {
+            SiunitxVersion::V2 => r#"\usepackage{siunitx}[=v2]"#,
+            SiunitxVersion::V3 => r#"\usepackage{siunitx}[=v3]"#,
+        }
+    }
+}
+
+impl DocumentOptions {
+    /// Reject options that would produce invalid TeX: a zero
+    /// [`DocumentOptions::font_size_pt`] or a [`DocumentOptions::max_width_cm`] that is not a
+    /// positive, finite width, and [`DocumentOptions::macros`] with names
+    /// [`DocumentBuilder::add_macro`] would refuse. Also rejects
+    /// [`DocumentOptions::externalize`] together with [`DocumentOptions::sandbox`], which would
+    /// need shell escape the sandbox forbids.
+    pub fn validate(&self) -> Result<(), BuildError> {
+        if self.font_size_pt == Some(0) {
+            return Err(BuildError::InvalidOptions(
+                "font_size_pt must be positive, got 0".to_string(),
+            ));
+        }
+
+        if let Some(width) = self.max_width_cm {
+            if !(width.is_finite() && width > 0.0) {
+                return Err(BuildError::InvalidOptions(format!(
+                    "max_width_cm must be positive and finite, got {}",
+                    width
+                )));
+            }
+        }
+
+        if let Some((name, _)) = self.macros.iter().find(|(name, _)| !is_macro_name(name)) {
+            return Err(BuildError::InvalidOptions(
+                InvalidMacroName(name.clone()).to_string(),
+            ));
+        }
+
+        if self.sandbox && self.externalize {
+            return Err(BuildError::InvalidOptions(
+                "externalize needs shell escape, turn off sandbox to use it".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The `\documentclass[class_options...]{document_class}` line.
+    pub fn documentclass(&self) -> String {
+        let mut class_options = self.class_options.clone();
+        if let Some(size @ 10..=12) = self.font_size_pt {
+            class_options.retain(|opt| !is_size_option(opt));
+            class_options.push(format!("{}pt", size));
+        }
+
+        if class_options.is_empty() {
+            format!(r#"\documentclass{{{}}}"#, self.document_class)
+        } else {
+            format!(
+                r#"\documentclass[{}]{{{}}}"#,
+                class_options.join(","),
+                self.document_class
+            )
+        }
+    }
+
+    /// [`DocumentOptions::preamble`] with [`DocumentOptions::geometry`],
+    /// [`DocumentOptions::macros`], [`DocumentOptions::siunitx_compat`] and
+    /// [`DocumentOptions::externalize`] applied.
+    ///
+    /// Repeated identical `\usepackage` lines, e.g. a default import added again, are emitted
+    /// once, where they first occur.
+    pub fn preamble_tex(&self) -> String {
+        let mut preamble = dedup_usepackages(&self.preamble);
+
+        if let Some(geometry) = &self.geometry {
+            preamble.push_str(&format!("{}\n", geometry.usepackage()));
+        }
+
+        let varwidth = r#"\usepackage{varwidth}"#;
+        if self.max_width_cm.is_some() && !preamble.lines().any(|line| line.trim() == varwidth) {
+            preamble.push_str(&format!("{}\n", varwidth));
+        }
+
+        for (name, expansion) in &self.macros {
+            preamble.push_str(&format!(
+                "\\newcommand{{\\{}}}{{{}}}\n",
+                name.strip_prefix('\\').unwrap_or(name),
+                expansion
+            ));
+        }
+
+        if self.externalize {
+            preamble.push_str(&format!(
+                r#"\usepackage{{tikz}}
+\usetikzlibrary{{external}}
+\tikzset{{external/system call={{latex \tikzexternalcheckshellescape -halt-on-error -interaction=batchmode -jobname "\image" "\texsource" && dvips -o "\image".ps "\image".dvi && ps2eps -f "\image.ps"}}}}
+\tikzexternalize[prefix={}/]
+"#,
+                self.externalize_dir().to_string_lossy().replace('\\', "/")
+            ));
+        }
+
+        let Some(version) = self.siunitx_compat else {
+            return preamble;
+        };
+
+        let siunitx = r#"\usepackage{siunitx}"#;
+        if preamble.lines().any(|line| line.trim() == siunitx) {
+            preamble
+                .lines()
+                .map(|line| {
+                    if line.trim() == siunitx {
+                        version.usepackage()
+                    } else {
+                        line
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+                + "\n"
+        } else {
+            format!("{}{}\n", preamble, version.usepackage())
+        }
+    }
+
+    /// [`DocumentOptions::externalize_dir`] or its default.
+    pub fn externalize_dir(&self) -> PathBuf {
+        self.externalize_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("tikz-cache"))
+    }
+
+    /// `\fontsize{}{}\selectfont` for sizes that have no matching class option, empty otherwise.
+    pub fn font_size(&self) -> String {
+        match self.font_size_pt {
+            Some(10..=12) | None => String::new(),
+            Some(size) => {
+                let skip = size as f32 * 1.2;
+                format!(r#"\fontsize{{{}}}{{{}}}\selectfont"#, size, skip)
+            }
+        }
+    }
+}
+
+/// `content` without trailing whitespace and `\par`s, see [`DocumentOptions::normalize_raw`].
+fn normalize_raw(content: &str) -> &str {
+    let mut content = content.trim_end();
+    while let Some(rest) = content.strip_suffix(r#"\par"#) {
+        // With an odd number of backslashes before it, the `\` of `\par` is escaped as in
+        // `x\\par`, a line break followed by the text "par".
+        if rest.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1 {
+            break;
+        }
+        content = rest.trim_end();
+    }
+    content
+}
+
+/// `preamble` without repeats of identical `\usepackage` lines, keeping the first occurrence.
+fn dedup_usepackages(preamble: &str) -> String {
+    let mut seen = HashSet::new();
+    preamble
+        .split_inclusive('\n')
+        .filter(|line| {
+            let line = line.trim();
+            !line.starts_with(r#"\usepackage"#) || seen.insert(line)
+        })
+        .collect()
+}
+
+/// Whether `name`, without its optional leading backslash, is a TeX control word.
+fn is_macro_name(name: &str) -> bool {
+    let name = name.strip_prefix('\\').unwrap_or(name);
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+/// A macro name that is not a control word, see [`DocumentBuilder::add_macro`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("`{0}` is not a valid macro name, expected letters only")]
+pub struct InvalidMacroName(pub String);
+
+/// Whether a class option is a font size such as `12pt`.
+fn is_size_option(opt: &str) -> bool {
+    opt.strip_suffix("pt")
+        .is_some_and(|size| size.parse::<u8>().is_ok())
 }
 
 impl Default for DocumentOptions {
@@ -23,6 +323,18 @@ impl Default for DocumentOptions {
         Self {
             text_color: Color::default(),
             preamble: DEFAULT_IMPORTS.to_string(),
+            document_class: "article".to_string(),
+            class_options: vec!["12pt".to_string()],
+            font_size_pt: None,
+            siunitx_compat: None,
+            externalize: false,
+            externalize_dir: None,
+            geometry: None,
+            macros: Vec::new(),
+            normalize_raw: true,
+            sandbox: true,
+            resource_dir: None,
+            max_width_cm: None,
         }
     }
 }
@@ -67,15 +379,135 @@ impl<T: TexString> Document<T> {
         self.content = content;
         self
     }
-}
 
-impl<T: TexString> TexString for Document<T> {
-    fn to_tex(&self) -> String {
-        let documentclass = r#"\documentclass[12pt]{article}"#;
-        let pagestyle = r#"\thispagestyle{empty}"#;
+    /// Check the content for unbalanced delimiters, see [`crate::tex::validate`].
+    pub fn validate(&self) -> Result<(), Vec<ValidationIssue>> {
+        tex::validate(&self.content.to_tex())
+    }
+
+    /// Unsafe primitives used in the content or macro expansions, empty if there are none or
+    /// [`DocumentOptions::sandbox`] is off. See [`crate::tex::unsafe_primitives`].
+    pub fn sandbox_violations(&self) -> Vec<String> {
+        if !self.options.sandbox {
+            return Vec::new();
+        }
+
+        let mut violations = tex::unsafe_primitives(&self.content.to_tex());
+        for (_, expansion) in &self.options.macros {
+            for violation in tex::unsafe_primitives(expansion) {
+                if !violations.contains(&violation) {
+                    violations.push(violation);
+                }
+            }
+        }
+
+        violations
+    }
+
+    /// Human-readable variant of [`TexString::to_tex`].
+    ///
+    /// Aligns `\usepackage` options into a column and separates the preamble, document setup
+    /// and content with blank lines. Meant for display, not for feeding the renderer.
+    pub fn to_tex_indent(&self) -> String {
+        let preamble = self.options.preamble_tex();
+        let lines = preamble
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+
+        let width = lines
+            .iter()
+            .filter_map(|line| split_usepackage(line))
+            .map(|(opts, _)| opts.len())
+            .max()
+            .unwrap_or(0);
+
+        let mut imports = String::new();
+        for line in lines {
+            match split_usepackage(line) {
+                Some((opts, rest)) => {
+                    imports.push_str(&format!("\\usepackage{:<width$}{}\n", opts, rest))
+                }
+                None => imports.push_str(&format!("{}\n", line)),
+            }
+        }
+
+        format!(
+            r#"% Generated by teximex
+{}
+
+{}
+
+{}
+{}
+{}{}
+
+{}
+
+{}
+"#,
+            self.options.documentclass(),
+            imports.trim_end(),
+            PAGE_STYLE,
+            r#"\begin{document}"#,
+            self.options.text_color.to_tex(),
+            self.options.font_size(),
+            self.body(),
+            r#"\end{document}"#,
+        )
+    }
+
+    /// The content as it is compiled, normalized as per [`DocumentOptions::normalize_raw`] and
+    /// wrapped as per [`DocumentOptions::max_width_cm`].
+    pub(crate) fn body(&self) -> String {
+        let content = self.content.to_tex();
+        let content = if self.options.normalize_raw {
+            normalize_raw(&content)
+        } else {
+            &content
+        };
+        self.wrap_width(content)
+    }
+
+    /// `content` in a `varwidth` box when [`DocumentOptions::max_width_cm`] is set.
+    fn wrap_width(&self, content: &str) -> String {
+        match self.options.max_width_cm {
+            Some(width) => format!(
+                "\\noindent\\begin{{varwidth}}{{{}cm}}\n{}\n\\end{{varwidth}}",
+                width, content
+            ),
+            None => content.to_string(),
+        }
+    }
+
+    /// Same as [`TexString::to_tex`] but with the preamble replaced by `\input{preamble_file}`.
+    ///
+    /// The file is expected to be written with [`Document::write_preamble_file`] and to be
+    /// reachable from wherever the document is compiled.
+    pub fn to_tex_with_input_file(&self, preamble_file: &Path) -> String {
+        let input = format!(
+            r#"\input{{{}}}"#,
+            preamble_file.to_string_lossy().replace('\\', "/")
+        );
+        self.to_tex_with_preamble(&input)
+    }
+
+    /// Write the preamble to `path` for use with [`Document::to_tex_with_input_file`].
+    pub fn write_preamble_file(&self, path: &Path) -> Result<(), std::io::Error> {
+        std::fs::write(path, self.options.preamble_tex())
+    }
+
+    fn to_tex_with_preamble(&self, preamble: &str) -> String {
+        let documentclass = self.options.documentclass();
+        let pagestyle = PAGE_STYLE;
         let begin = r#"\begin{document}"#;
-        let color = &self.options.text_color.to_tex();
-        let content = &self.content.to_tex();
+        let color = format!(
+            "{}{}",
+            self.options.text_color.to_tex(),
+            self.options.font_size()
+        );
+        let content = self.body();
         let end = r#"\end{document}"#;
 
         format!(
@@ -87,32 +519,211 @@ impl<T: TexString> TexString for Document<T> {
 {}
 {}
 "#,
-            documentclass,
-            &self.options.preamble.to_tex(),
-            pagestyle,
-            begin,
-            color,
-            content,
-            end,
+            documentclass, preamble, pagestyle, begin, color, content, end,
         )
     }
 }
 
+impl Document<String> {
+    /// Read a `.tex` fragment from `path` as the raw content of a document with default options.
+    pub fn from_tex_file<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        std::fs::read_to_string(path).map(Document::new)
+    }
+
+    /// A chemical formula or equation in `mhchem` syntax, e.g. `2H2 + O2 -> 2H2O`.
+    ///
+    /// Loads `mhchem` and wraps `formula` in `\ce{...}`.
+    pub fn chemistry(formula: &str) -> Self {
+        let mut builder = Document::builder(format!(r#"\ce{{{}}}"#, formula));
+        builder.add_usepackage("mhchem", &["version=4"]);
+        builder.build().expect(r#"`\ce{}` is never blank"#)
+    }
+
+    /// The math selected by the byte `range` of `full`, e.g. a selection in an editor.
+    ///
+    /// The math mode follows the `$...$`, `$$...$$`, `\(...\)` or `\[...\]` the selection is in
+    /// or touches, displayed when there are none. A selection within a formula renders just
+    /// the selected part, one that includes or straddles delimiters, or an empty one inside a
+    /// formula, renders the whole formula. A selection touching more than one formula is
+    /// ambiguous and fails with [`BuildError::InvalidSelection`].
+    pub fn from_selection(full: &str, range: Range<usize>) -> Result<Self, BuildError> {
+        if full.get(range.clone()).is_none() {
+            return Err(BuildError::InvalidSelection(range));
+        }
+
+        let mut spans = math_spans(full)
+            .into_iter()
+            .filter(|span| span.outer.start < range.end && range.start < span.outer.end);
+        let span = spans.next();
+        if spans.next().is_some() {
+            return Err(BuildError::InvalidSelection(range));
+        }
+
+        let (content, mode) = match span {
+            Some(span)
+                if !range.is_empty()
+                    && span.inner.start <= range.start
+                    && range.end <= span.inner.end =>
+            {
+                (&full[range], span.mode)
+            }
+            Some(span) => (&full[span.inner], span.mode),
+            None => (&full[range], DocumentMathMode::default()),
+        };
+
+        Document::builder(content.trim().to_string())
+            .mathmode(mode)
+            .build()
+    }
+}
+
+/// Delimiters [`math_spans`] looks for, as opener, closer and the mode they stand for.
+const MATH_DELIMITERS: &[(&str, &str, DocumentMathMode)] = &[
+    ("$$", "$$", DocumentMathMode::Displayed),
+    ("$", "$", DocumentMathMode::Inline),
+    (r#"\["#, r#"\]"#, DocumentMathMode::Displayed),
+    (r#"\("#, r#"\)"#, DocumentMathMode::Inline),
+];
+
+/// A delimited formula in a larger text, see [`math_spans`].
+struct MathSpan {
+    /// Byte range including the delimiters.
+    outer: Range<usize>,
+    /// Byte range between the delimiters.
+    inner: Range<usize>,
+    mode: DocumentMathMode,
+}
+
+/// The delimited formulas in `text` in order, skipping escaped characters such as `\$`.
+/// Unterminated formulas are left out.
+fn math_spans(text: &str) -> Vec<MathSpan> {
+    let bytes = text.as_bytes();
+    let mut spans = Vec::new();
+    let mut open: Option<(usize, usize, &str, DocumentMathMode)> = None;
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match open {
+            Some((start, inner, closer, mode)) if bytes[i..].starts_with(closer.as_bytes()) => {
+                spans.push(MathSpan {
+                    outer: start..i + closer.len(),
+                    inner: inner..i,
+                    mode,
+                });
+                open = None;
+                i += closer.len();
+                continue;
+            }
+            None => {
+                let opener = MATH_DELIMITERS
+                    .iter()
+                    .find(|(opener, ..)| bytes[i..].starts_with(opener.as_bytes()));
+                if let Some((opener, closer, mode)) = opener {
+                    open = Some((i, i + opener.len(), closer, *mode));
+                    i += opener.len();
+                    continue;
+                }
+            }
+            _ => {}
+        }
+
+        i += if bytes[i] == b'\\' { 2 } else { 1 };
+    }
+
+    spans
+}
+
+impl From<String> for Document<String> {
+    fn from(content: String) -> Self {
+        Document::new(content)
+    }
+}
+
+impl From<&str> for Document<String> {
+    fn from(content: &str) -> Self {
+        Document::new(content.to_string())
+    }
+}
+
+/// Split a `\usepackage[opts]{name}` line into its `[opts]` and `{name}` parts.
+fn split_usepackage(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix(r#"\usepackage"#)?;
+    if rest.starts_with('[') {
+        let end = rest.find(']')? + 1;
+        Some((&rest[..end], &rest[end..]))
+    } else {
+        Some(("", rest))
+    }
+}
+
+impl<T: TexString> TexString for Document<T> {
+    fn to_tex(&self) -> String {
+        self.to_tex_with_preamble(&self.options.preamble_tex())
+    }
+}
+
 /// Refers to [`crate::tex::MathMode`]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DocumentMathMode {
     /// Refers to [`crate::tex::MathMode::Inline`]
     Inline,
     /// Refers to [`crate::tex::MathMode::Displayed`]
+    #[default]
     Displayed,
+    /// Refers to [`crate::tex::MathMode::Equation`]
+    Equation { number: bool },
+    /// Refers to [`crate::tex::MathMode::Align`]
+    Align,
+    /// Refers to [`crate::tex::MathMode::Aligned`]
+    Aligned,
+    /// Refers to [`crate::tex::MathMode::Cases`]
+    Cases,
 }
 
 impl DocumentMathMode {
     /// Transform from [`Self`] to [`crate::tex::MathMode`] applying a [`crate::tex::TexString`]
     pub fn transform<T: TexString>(&self, tex: T) -> MathMode<T> {
+        self.transform_tokens(vec![tex])
+    }
+
+    /// Like [`Self::transform`] but with several tokens
+    pub fn transform_tokens<T: TexString>(&self, tokens: Vec<T>) -> MathMode<T> {
         match self {
-            DocumentMathMode::Inline => MathMode::Inline(vec![tex]),
-            DocumentMathMode::Displayed => MathMode::Displayed(vec![tex]),
+            DocumentMathMode::Inline => MathMode::Inline(tokens),
+            DocumentMathMode::Displayed => MathMode::Displayed(tokens),
+            DocumentMathMode::Equation { number } => MathMode::Equation {
+                number: *number,
+                tokens,
+            },
+            DocumentMathMode::Align => MathMode::Align(tokens),
+            DocumentMathMode::Aligned => MathMode::Aligned(tokens),
+            DocumentMathMode::Cases => MathMode::Cases(tokens),
+        }
+    }
+}
+
+/// A string [`DocumentMathMode`] can't be parsed from.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error(
+    "unknown math mode `{0}`, expected inline, displayed, equation, equation*, align, aligned or cases"
+)]
+pub struct UnknownMathMode(pub String);
+
+/// Parses the lowercase variant names, `equation` being numbered and `equation*` not.
+impl std::str::FromStr for DocumentMathMode {
+    type Err = UnknownMathMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inline" => Ok(DocumentMathMode::Inline),
+            "displayed" => Ok(DocumentMathMode::Displayed),
+            "equation" => Ok(DocumentMathMode::Equation { number: true }),
+            "equation*" => Ok(DocumentMathMode::Equation { number: false }),
+            "align" => Ok(DocumentMathMode::Align),
+            "aligned" => Ok(DocumentMathMode::Aligned),
+            "cases" => Ok(DocumentMathMode::Cases),
+            _ => Err(UnknownMathMode(s.to_string())),
         }
     }
 }
@@ -125,17 +736,38 @@ pub mod state {
 pub struct DocumentBuilder<State = state::MathModeUnapplied> {
     options: DocumentOptions,
     content: String,
+    /// Whether the content was blank before any math mode was applied around it.
+    blank: bool,
     _state: std::marker::PhantomData<State>,
 }
 
+/// Errors produced by [`DocumentBuilder::build`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BuildError {
+    /// The content is empty or whitespace only, which compiles to a blank page.
+    #[error("document content is empty")]
+    EmptyContent,
+    /// The range passed to [`Document::from_selection`] is out of bounds, splits a character or
+    /// touches more than one formula.
+    #[error(
+        "selection {0:?} is out of bounds, not on character boundaries or spans several formulas"
+    )]
+    InvalidSelection(Range<usize>),
+    /// The [`DocumentOptions`] can't be compiled, see [`DocumentOptions::validate`].
+    #[error("invalid document options: {0}")]
+    InvalidOptions(String),
+}
+
 // TODO: Optimise out redundant `.clone()`
 
 impl<S> DocumentBuilder<S> {
     pub fn new<T: TexString>(content: T) -> Self {
+        let content = content.to_tex();
         DocumentBuilder {
             options: DocumentOptions::default(),
-            content: content.to_tex(),
-            _state: PhantomData::default(),
+            blank: content.trim().is_empty(),
+            content,
+            _state: PhantomData,
         }
     }
 
@@ -149,17 +781,47 @@ impl<S> DocumentBuilder<S> {
         self
     }
 
+    /// Append `\usepackage[options...]{name}` to the preamble.
+    pub fn add_usepackage(&mut self, name: &str, options: &[&str]) -> &mut Self {
+        let package = Usepackage::new_with_arbitrary(name, options.to_vec());
+        self.add_preamble(format!("{}\n", package.to_tex()))
+    }
+
+    /// Define `name` (e.g. `\R` or `R`) to expand to `expansion`, see
+    /// [`DocumentOptions::macros`]. Fails unless `name` consists of ASCII letters only.
+    pub fn add_macro(
+        &mut self,
+        name: &str,
+        expansion: &str,
+    ) -> Result<&mut Self, InvalidMacroName> {
+        if !is_macro_name(name) {
+            return Err(InvalidMacroName(name.to_string()));
+        }
+
+        self.options
+            .macros
+            .push((name.to_string(), expansion.to_string()));
+        Ok(self)
+    }
+
     pub fn color(&mut self, color: crate::tex::Color) -> &mut Self {
         let mut opt = self.options.clone();
         opt.text_color = color;
         self.options(opt)
     }
 
-    pub fn build(self) -> Document<String> {
-        Document {
+    /// Fails with [`BuildError::EmptyContent`] if the content is blank, even when wrapped in a
+    /// math mode, and with [`BuildError::InvalidOptions`] if [`DocumentOptions::validate`] does.
+    pub fn build(self) -> Result<Document<String>, BuildError> {
+        if self.blank {
+            return Err(BuildError::EmptyContent);
+        }
+        self.options.validate()?;
+
+        Ok(Document {
             options: self.options,
             content: self.content,
-        }
+        })
     }
 }
 
@@ -169,7 +831,480 @@ impl DocumentBuilder<state::MathModeUnapplied> {
         DocumentBuilder::<state::MathModeApplied> {
             options: self.options,
             content: self.content,
-            _state: PhantomData::default(),
+            blank: self.blank,
+            _state: PhantomData,
+        }
+    }
+}
+
+impl DocumentBuilder<state::MathModeApplied> {
+    /// Start a builder whose content is `tokens` in `mode`, the same as building from
+    /// [`MathMode`] directly.
+    pub fn mathmode_tokens<T: TexString>(mode: DocumentMathMode, tokens: Vec<T>) -> Self {
+        let blank = tokens.iter().all(|tok| tok.to_tex().trim().is_empty());
+        DocumentBuilder {
+            blank,
+            ..DocumentBuilder::new(mode.transform_tokens(tokens))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod document {
+        use std::path::{Path, PathBuf};
+
+        use crate::{
+            document::{
+                BuildError, Document, DocumentBuilder, DocumentMathMode, DocumentOptions, Geometry,
+                InvalidMacroName, SiunitxVersion, UnknownMathMode,
+            },
+            tex::{Color, MathMode, TexString},
+        };
+
+        #[test]
+        fn documentclass() {
+            let mut doc = Document::new("x^2");
+            assert!(doc.to_tex().starts_with("\\documentclass[12pt]{article}\n"));
+
+            let options = DocumentOptions {
+                document_class: "standalone".to_string(),
+                class_options: Vec::new(),
+                ..Default::default()
+            };
+            doc.set_options(options);
+            assert!(doc.to_tex().starts_with("\\documentclass{standalone}\n"));
+        }
+
+        #[test]
+        fn font_size_pt() {
+            let mut options = DocumentOptions {
+                font_size_pt: Some(10),
+                ..Default::default()
+            };
+            let doc = Document::new_with_options("x^2", options.clone());
+            assert!(doc.to_tex().starts_with("\\documentclass[10pt]{article}\n"));
+
+            options.font_size_pt = Some(20);
+            let doc = Document::new_with_options("x^2", options);
+            let tex = doc.to_tex();
+            assert!(tex.starts_with("\\documentclass[12pt]{article}\n"));
+            assert!(tex.contains(r#"\color{black}\fontsize{20}{24}\selectfont"#));
+
+            let options = DocumentOptions {
+                font_size_pt: Some(0),
+                ..Default::default()
+            };
+            assert!(matches!(
+                options.validate(),
+                Err(BuildError::InvalidOptions(_))
+            ));
+        }
+
+        #[test]
+        fn rgb_color() {
+            let options = DocumentOptions {
+                text_color: Color::from_hex(0xFF8800),
+                ..Default::default()
+            };
+            let tex = Document::new_with_options("x^2", options).to_tex();
+
+            assert!(tex.contains("\\begin{document}\n\\color[HTML]{ff8800}\n"));
+            assert!(!tex.contains(r#"\definecolor"#));
+        }
+
+        #[test]
+        fn siunitx_compat() {
+            let mut options = DocumentOptions {
+                siunitx_compat: Some(SiunitxVersion::V2),
+                ..Default::default()
+            };
+            let tex = Document::new_with_options(r#"\SI{3}{\metre}"#, options.clone()).to_tex();
+
+            assert!(tex.contains("\\usepackage{siunitx}[=v2]\n"));
+            assert_eq!(tex.matches("siunitx").count(), 1);
+
+            options.preamble = String::new();
+            options.siunitx_compat = Some(SiunitxVersion::V3);
+            assert_eq!(options.preamble_tex(), "\\usepackage{siunitx}[=v3]\n");
+        }
+
+        #[test]
+        fn externalize() {
+            let mut options = DocumentOptions::default();
+            assert!(!options.preamble_tex().contains("tikzexternalize"));
+
+            options.externalize = true;
+            assert!(options
+                .preamble_tex()
+                .contains(r#"\tikzexternalize[prefix=tikz-cache/]"#));
+
+            options.externalize_dir = Some(PathBuf::from("/var/cache/figures"));
+            assert!(options
+                .preamble_tex()
+                .contains(r#"\tikzexternalize[prefix=/var/cache/figures/]"#));
+
+            assert!(matches!(
+                options.validate(),
+                Err(BuildError::InvalidOptions(_))
+            ));
+            options.sandbox = false;
+            assert!(options.validate().is_ok());
+        }
+
+        #[test]
+        fn geometry() {
+            let mut options = DocumentOptions::default();
+            assert_eq!(options.preamble_tex(), options.preamble);
+
+            options.geometry = Some(Geometry {
+                paper: Some("a3paper".to_string()),
+                margin: Some("1cm".to_string()),
+                landscape: true,
+                ..Default::default()
+            });
+            assert!(options
+                .preamble_tex()
+                .ends_with("\\usepackage[a3paper,margin=1cm,landscape]{geometry}\n"));
+
+            options.geometry = Some(Geometry {
+                paper: Some("a3paper".to_string()),
+                paper_size: Some(("40cm".to_string(), "10cm".to_string())),
+                ..Default::default()
+            });
+            assert!(options
+                .preamble_tex()
+                .contains(r#"\usepackage[paperwidth=40cm,paperheight=10cm]{geometry}"#));
+        }
+
+        #[test]
+        fn macros() {
+            let mut builder = Document::builder(r#"x \in \R"#);
+            assert!(builder.add_macro(r#"\R"#, r#"\mathbb{R}"#).is_ok());
+            assert!(builder.add_macro("eps", r#"\varepsilon"#).is_ok());
+
+            assert_eq!(
+                builder.add_macro(r#"\my_macro"#, "x").err(),
+                Some(InvalidMacroName(r#"\my_macro"#.to_string()))
+            );
+            assert!(builder.add_macro(r#"\"#, "x").is_err());
+
+            let tex = builder.build().unwrap().to_tex();
+            assert!(
+                tex.contains("\\newcommand{\\R}{\\mathbb{R}}\n\\newcommand{\\eps}{\\varepsilon}\n")
+            );
+            assert!(tex.find(r#"\newcommand"#) < tex.find(r#"\begin{document}"#));
+
+            // Set directly, skipping the builder's check.
+            let options = DocumentOptions {
+                macros: vec![(r#"\my_macro"#.to_string(), "x".to_string())],
+                ..Default::default()
+            };
+            assert!(matches!(
+                options.validate(),
+                Err(BuildError::InvalidOptions(_))
+            ));
+        }
+
+        #[test]
+        fn normalize_raw() {
+            let mut doc = Document::new("Some text.\n\n\\par  \n\t\n".to_string());
+            assert!(doc
+                .to_tex()
+                .contains("\\color{black}\nSome text.\n\\end{document}"));
+
+            // `\parbox` and the like are not `\par`.
+            doc.set_content(r#"\fbox{x}\parbox"#.to_string());
+            assert!(doc.to_tex().contains("\\parbox\n\\end{document}"));
+
+            // A line break followed by "par" is kept, while `\par` after one is stripped.
+            doc.set_content(r#"x\\par"#.to_string());
+            assert!(doc.to_tex().contains("\nx\\\\par\n\\end{document}"));
+            doc.set_content(r#"x\\\par"#.to_string());
+            assert!(doc.to_tex().contains("\nx\\\\\n\\end{document}"));
+
+            let mut options = doc.options().clone();
+            options.normalize_raw = false;
+            let doc = Document::new_with_options("x\n\n".to_string(), options);
+            assert!(doc.to_tex().contains("\nx\n\n\n\\end{document}"));
+        }
+
+        #[test]
+        fn sandbox() {
+            let mut builder = Document::builder(r#"\input{/etc/passwd} x"#);
+            builder
+                .add_macro("evil", r#"\immediate\write18{id}"#)
+                .unwrap();
+            let doc = builder.build().unwrap();
+
+            assert_eq!(
+                doc.sandbox_violations(),
+                vec![r#"\input"#, r#"\immediate"#, r#"\write"#]
+            );
+
+            let mut options = doc.options().clone();
+            options.sandbox = false;
+            let doc = Document::new_with_options(doc.content().clone(), options);
+            assert!(doc.sandbox_violations().is_empty());
+        }
+
+        #[test]
+        fn to_tex_indent() {
+            let doc = Document::new("x^2");
+            let tex = doc.to_tex_indent();
+
+            assert!(tex.starts_with("% Generated by teximex\n"));
+
+            let columns = tex
+                .lines()
+                .filter(|line| line.contains(r#"\usepackage"#))
+                .map(|line| (line.find(r#"\usepackage"#), line.rfind('{')))
+                .collect::<Vec<_>>();
+
+            assert!(!columns.is_empty());
+            assert!(columns.iter().all(|&col| col == columns[0]));
+
+            // The same content normalization applies as when compiling.
+            let doc = Document::new("x^2 \\par\n\n");
+            assert!(doc.to_tex_indent().contains("\nx^2\n"));
+            assert!(!doc.to_tex_indent().contains(r#"\par"#));
+
+            let options = DocumentOptions {
+                normalize_raw: false,
+                ..Default::default()
+            };
+            let doc = Document::new_with_options("x^2 \\par", options);
+            assert!(doc.to_tex_indent().contains("\nx^2 \\par\n"));
+            assert!(doc.to_tex().contains("\nx^2 \\par\n"));
+        }
+
+        #[test]
+        fn align() {
+            let doc = Document::builder(r#"x + y &= 2 \\ x - y &= 0"#)
+                .mathmode(DocumentMathMode::Align)
+                .build()
+                .unwrap();
+
+            assert!(doc.to_tex().contains(
+                r#"\begin{document}
+\color{black}
+\begin{align*} x + y &= 2 \\ x - y &= 0 \end{align*}
+\end{document}"#
+            ));
+        }
+
+        #[test]
+        fn dedup_usepackage() {
+            let mut builder = Document::builder("x^2");
+            builder.add_usepackage("amsmath", &[]);
+            builder.add_usepackage("amsmath", &["fleqn"]);
+            let tex = builder.build().unwrap().to_tex();
+
+            assert_eq!(tex.matches("\\usepackage{amsmath}\n").count(), 1);
+            assert_eq!(tex.matches("\\usepackage[fleqn]{amsmath}\n").count(), 1);
+            assert!(tex.find(r#"\usepackage{amsmath}"#) < tex.find(r#"\usepackage{amssymb}"#));
+        }
+
+        #[test]
+        fn mathmode_tokens() {
+            let tokens = vec![r#"\sqrt{2}"#, "i^2"];
+            let doc = DocumentBuilder::mathmode_tokens(DocumentMathMode::Inline, tokens.clone())
+                .build()
+                .unwrap();
+
+            assert_eq!(doc.content(), &MathMode::Inline(tokens).to_tex());
+            assert_eq!(doc.content(), r#"\( \sqrt{2}i^2 \)"#);
+        }
+
+        #[test]
+        fn build_empty() {
+            assert_eq!(
+                Document::builder("").build().unwrap_err(),
+                BuildError::EmptyContent
+            );
+            assert_eq!(
+                Document::builder(" \n\t")
+                    .mathmode(DocumentMathMode::Displayed)
+                    .build()
+                    .unwrap_err(),
+                BuildError::EmptyContent
+            );
+            assert_eq!(
+                DocumentBuilder::mathmode_tokens(DocumentMathMode::Inline, vec!["", " "])
+                    .build()
+                    .unwrap_err(),
+                BuildError::EmptyContent
+            );
+            assert!(Document::builder("x").build().is_ok());
+        }
+
+        #[test]
+        fn add_usepackage() {
+            let mut builder = Document::builder("x^2");
+            builder.add_usepackage("geometry", &["margin=1in"]);
+            let doc = builder.build().unwrap();
+
+            assert!(doc
+                .options()
+                .preamble
+                .ends_with("\\usepackage[margin=1in]{geometry}\n"));
+        }
+
+        #[test]
+        fn from_tex_file() {
+            let path = std::env::temp_dir().join(format!("teximex-{}.tex", std::process::id()));
+            std::fs::write(&path, r#"\[ \int_0^1 x \, dx \]"#).unwrap();
+
+            let doc = Document::from_tex_file(&path).unwrap();
+            assert_eq!(doc.content(), r#"\[ \int_0^1 x \, dx \]"#);
+
+            std::fs::remove_file(&path).unwrap();
+            assert!(Document::from_tex_file(&path).is_err());
+        }
+
+        #[test]
+        fn chemistry() {
+            let tex = Document::chemistry("2H2 + O2 -> 2H2O").to_tex();
+
+            assert!(tex.contains("\\usepackage[version=4]{mhchem}\n"));
+            assert!(tex.contains("\n\\ce{2H2 + O2 -> 2H2O}\n"));
+        }
+
+        #[test]
+        fn from_str_and_string() {
+            let from_str: Document<String> = "x^2".into();
+            let from_string = Document::from("x^2".to_string());
+
+            assert_eq!(from_str, Document::new("x^2".to_string()));
+            assert_eq!(from_str, from_string);
+        }
+
+        #[test]
+        fn max_width() {
+            let options = DocumentOptions {
+                max_width_cm: Some(7.5),
+                ..Default::default()
+            };
+            let doc = Document::new_with_options("A long paragraph.".to_string(), options);
+
+            let tex = doc.to_tex();
+            assert_eq!(tex.matches(r#"\usepackage{varwidth}"#).count(), 1);
+            assert!(tex.contains(
+                "\\noindent\\begin{varwidth}{7.5cm}\nA long paragraph.\n\\end{varwidth}\n"
+            ));
+            assert!(doc.to_tex_indent().contains(r#"\begin{varwidth}{7.5cm}"#));
+
+            let plain = Document::new("A long paragraph.".to_string()).to_tex();
+            assert!(!plain.contains("varwidth"));
+
+            for invalid in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+                let options = DocumentOptions {
+                    max_width_cm: Some(invalid),
+                    ..Default::default()
+                };
+                assert!(matches!(
+                    options.validate(),
+                    Err(BuildError::InvalidOptions(_))
+                ));
+
+                let mut builder = Document::builder("x");
+                builder.options(options);
+                assert!(matches!(
+                    builder.build(),
+                    Err(BuildError::InvalidOptions(_))
+                ));
+            }
+        }
+
+        #[test]
+        fn from_selection() {
+            let full = r#"Let $x^2 + 1$ and \[ \int f \] cost \$5."#;
+            let select = |needle: &str| {
+                let start = full.find(needle).unwrap();
+                Document::from_selection(full, start..start + needle.len())
+                    .map(|doc| doc.content().clone())
+            };
+
+            assert_eq!(select("$x^2 + 1$"), Ok(r#"\( x^2 + 1 \)"#.to_string()));
+            assert_eq!(select("x^2"), Ok(r#"\( x^2 \)"#.to_string()));
+            assert_eq!(select("+ 1$ and"), Ok(r#"\( x^2 + 1 \)"#.to_string()));
+            assert_eq!(select(r#"\int"#), Ok(r#"\[ \int \]"#.to_string()));
+            assert_eq!(select("cost"), Ok(r#"\[ cost \]"#.to_string()));
+            assert_eq!(select("5"), Ok(r#"\[ 5 \]"#.to_string()));
+
+            // An empty selection inside a formula selects all of it.
+            let caret = full.find("int").unwrap();
+            assert_eq!(
+                Document::from_selection(full, caret..caret).map(|doc| doc.content().clone()),
+                Ok(r#"\[ \int f \]"#.to_string())
+            );
+
+            assert_eq!(
+                Document::from_selection(full, 0..0),
+                Err(BuildError::EmptyContent)
+            );
+            assert_eq!(
+                Document::from_selection(full, 10..1000),
+                Err(BuildError::InvalidSelection(10..1000))
+            );
+
+            // Covering two formulas doesn't say which one to render.
+            let start = full.find("$x").unwrap();
+            let end = full.find("f").unwrap();
+            assert_eq!(
+                Document::from_selection(full, start..end),
+                Err(BuildError::InvalidSelection(start..end))
+            );
+        }
+
+        #[test]
+        fn math_mode_from_str() {
+            assert_eq!("inline".parse(), Ok(DocumentMathMode::Inline));
+            assert_eq!("displayed".parse(), Ok(DocumentMathMode::Displayed));
+            assert_eq!(
+                "equation".parse(),
+                Ok(DocumentMathMode::Equation { number: true })
+            );
+            assert_eq!(
+                "equation*".parse(),
+                Ok(DocumentMathMode::Equation { number: false })
+            );
+            assert_eq!("cases".parse(), Ok(DocumentMathMode::Cases));
+
+            for invalid in ["", "Inline", "raw", "display"] {
+                assert_eq!(
+                    invalid.parse::<DocumentMathMode>(),
+                    Err(UnknownMathMode(invalid.to_string()))
+                );
+            }
+        }
+
+        #[test]
+        fn clone() {
+            let mut options = DocumentOptions::default();
+            options
+                .macros
+                .push(("R".to_string(), r#"\mathbb{R}"#.to_string()));
+            options.resource_dir = Some(PathBuf::from("styles"));
+            let doc = Document::new_with_options(r#"x \in \R"#.to_string(), options);
+
+            let mut snapshot = doc.clone();
+            assert_eq!(snapshot, doc);
+            assert_eq!(snapshot.to_tex(), doc.to_tex());
+
+            snapshot.set_content("y".to_string());
+            assert_ne!(snapshot, doc);
+            assert_eq!(doc.content(), r#"x \in \R"#);
+        }
+
+        #[test]
+        fn to_tex_with_input_file() {
+            let doc = Document::new("x^2");
+            let tex = doc.to_tex_with_input_file(Path::new("build/preamble.tex"));
+
+            assert!(tex.contains(r#"\input{build/preamble.tex}"#));
+            assert!(!tex.contains(r#"\usepackage"#));
         }
     }
 }
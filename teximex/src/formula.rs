@@ -0,0 +1,186 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    document::{BuildError, Document, DocumentMathMode},
+    render::{RenderError, RenderFormat, RenderOptions},
+    tex::Color,
+};
+
+/// A formula to render with a fluent API, for when the defaults of [`Document`] and
+/// [`RenderOptions`] are almost right.
+///
+/// ```no_run
+/// use teximex::{formula::Formula, tex::Color};
+///
+/// let png = Formula::new("x^2").scale(2.0).color(Color::White).inline().render()?;
+/// # Ok::<(), teximex::formula::FormulaError>(())
+/// ```
+///
+/// Renders with the native backend in a fresh temporary directory that is removed afterwards.
+/// Use [`crate::document::DocumentBuilder`] and [`crate::render::native::RenderInstanceNative`]
+/// directly for anything beyond this.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Formula {
+    content: String,
+    mode: DocumentMathMode,
+    color: Color,
+    scale: Option<f32>,
+    margin: Option<f32>,
+    format: RenderFormat,
+}
+
+/// Errors produced by [`Formula::render`].
+#[derive(Debug, thiserror::Error)]
+pub enum FormulaError {
+    #[error(transparent)]
+    Build(#[from] BuildError),
+    #[error(transparent)]
+    Render(#[from] RenderError),
+}
+
+impl Formula {
+    /// Displayed math `content` in black, rendered to PNG at the default scale and margin.
+    pub fn new<S: Into<String>>(content: S) -> Self {
+        Self {
+            content: content.into(),
+            mode: DocumentMathMode::default(),
+            color: Color::default(),
+            scale: None,
+            margin: None,
+            format: RenderFormat::default(),
+        }
+    }
+
+    pub fn scale(mut self, scale: f32) -> Self {
+        self.scale = Some(scale);
+        self
+    }
+
+    /// Border around the formula in pixels, see [`RenderOptions::margin`].
+    pub fn margin(mut self, margin: f32) -> Self {
+        self.margin = Some(margin);
+        self
+    }
+
+    pub fn color(mut self, color: Color) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn mode(mut self, mode: DocumentMathMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Shorthand for [`DocumentMathMode::Inline`].
+    pub fn inline(self) -> Self {
+        self.mode(DocumentMathMode::Inline)
+    }
+
+    /// Shorthand for [`DocumentMathMode::Displayed`], the default.
+    pub fn displayed(self) -> Self {
+        self.mode(DocumentMathMode::Displayed)
+    }
+
+    pub fn format(mut self, format: RenderFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// The document [`Formula::render`] renders.
+    pub fn document(&self) -> Result<Document<String>, BuildError> {
+        let mut builder = Document::builder(self.content.clone()).mathmode(self.mode);
+        builder.color(self.color.clone());
+        builder.build()
+    }
+
+    /// The options [`Formula::render`] renders with.
+    pub fn options(&self) -> RenderOptions {
+        let mut options = RenderOptions::new(self.scale, self.margin);
+        options.set_format(self.format);
+        options
+    }
+
+    /// Render to an encoded image, PNG unless [`Formula::format`] says otherwise.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn render(&self) -> Result<Vec<u8>, FormulaError> {
+        let document = self.document()?;
+
+        let root = temp_root();
+        std::fs::create_dir_all(&root).map_err(RenderError::from)?;
+        let result = crate::render_document(&document, self.options(), &root);
+        let _ = std::fs::remove_dir_all(&root);
+
+        Ok(result?)
+    }
+}
+
+/// A directory under the system temporary directory unique to this call.
+fn temp_root() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    std::env::temp_dir().join(format!(
+        "teximex-formula-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    mod formula {
+        use crate::{
+            document::{BuildError, DocumentMathMode},
+            formula::{temp_root, Formula, FormulaError},
+            render::{RenderError, RenderFormat},
+            tex::Color,
+        };
+
+        #[test]
+        fn fluent() {
+            let formula = Formula::new("x^2")
+                .scale(2.0)
+                .margin(0.0)
+                .color(Color::White)
+                .inline()
+                .format(RenderFormat::Webp);
+
+            let document = formula.document().unwrap();
+            assert_eq!(document.content(), r#"\( x^2 \)"#);
+            assert_eq!(document.options().text_color, Color::White);
+
+            let options = formula.options();
+            assert_eq!(options.scale(), 2.0);
+            assert_eq!(options.margin(), 0.0);
+            assert_eq!(options.format(), RenderFormat::Webp);
+
+            assert_eq!(
+                Formula::new("x").document().unwrap().content(),
+                r#"\[ x \]"#
+            );
+            assert_eq!(
+                Formula::new("x").inline().displayed(),
+                Formula::new("x").mode(DocumentMathMode::Displayed)
+            );
+        }
+
+        #[test]
+        fn render() {
+            assert!(matches!(
+                Formula::new("  ").render(),
+                Err(FormulaError::Build(BuildError::EmptyContent))
+            ));
+
+            // Needs a TeX installation, the temporary directory is removed either way.
+            match Formula::new("x^2").render() {
+                Ok(png) => assert!(png.starts_with(b"\x89PNG")),
+                Err(FormulaError::Render(RenderError::BackendUnavailable(_))) => {}
+                Err(err) => panic!("{}", err),
+            }
+            assert_ne!(temp_root(), temp_root());
+        }
+    }
+}
@@ -1,3 +1,84 @@
 pub mod document;
+pub mod formula;
 pub mod render;
 pub mod tex;
+
+/// Render `document` with the native backend in one call, returning the encoded image.
+///
+/// Intermediate files are written to `root`. Use [`render::native::RenderInstanceNative`]
+/// directly for logs, caching or batches, or [`formula::Formula`] for a single formula.
+///
+/// ```no_run
+/// use teximex::{document::Document, render::RenderOptions};
+///
+/// let document = Document::new(r#"\[ e^{i\pi} + 1 = 0 \]"#.to_string());
+/// let png = teximex::render_document(
+///     &document,
+///     RenderOptions::default(),
+///     &std::env::temp_dir(),
+/// )?;
+/// # Ok::<(), teximex::render::RenderError>(())
+/// ```
+#[cfg(not(target_arch = "wasm32"))]
+pub fn render_document(
+    document: &document::Document<String>,
+    options: render::RenderOptions,
+    root: &std::path::Path,
+) -> Result<Vec<u8>, render::RenderError> {
+    use render::RenderBackend;
+
+    let instance = render::RenderInstance::new_with_options(options).load(document.clone());
+    render::native::RenderInstanceNative::new(root, instance).render()
+}
+
+/// The `arboard` version the clipboard functions take, so applications holding a
+/// [`arboard::Clipboard`] don't have to keep their own dependency in step.
+#[cfg(feature = "clipboard")]
+pub use arboard;
+
+/// Errors produced when copying a rendered image to the clipboard.
+#[cfg(feature = "clipboard")]
+#[derive(Debug, thiserror::Error)]
+pub enum ClipboardError {
+    #[error("failed to decode image: {0}")]
+    Decode(#[from] image::ImageError),
+    #[error("failed to access the clipboard: {0}")]
+    Clipboard(#[from] arboard::Error),
+}
+
+/// Decode an encoded image such as the output of [`render::RenderBackend::render`] and put it on
+/// the system clipboard.
+///
+/// On X11 the clipboard is only served while a [`arboard::Clipboard`] is alive, so long-running
+/// applications should keep one around and use [`copy_image_to`] instead.
+#[cfg(feature = "clipboard")]
+pub fn copy_png_to_clipboard(png: &[u8]) -> Result<(), ClipboardError> {
+    copy_image_to(&mut arboard::Clipboard::new()?, png)
+}
+
+/// Like [`copy_png_to_clipboard`] but with an existing `clipboard`.
+#[cfg(feature = "clipboard")]
+pub fn copy_image_to(
+    clipboard: &mut arboard::Clipboard,
+    image: &[u8],
+) -> Result<(), ClipboardError> {
+    copy_rgba_image_to(clipboard, &image::load_from_memory(image)?.into_rgba8())
+}
+
+/// Like [`copy_image_to`] but with an already decoded `image`, such as the output of
+/// [`render::native::RenderInstanceNative::render_image`].
+#[cfg(feature = "clipboard")]
+pub fn copy_rgba_image_to(
+    clipboard: &mut arboard::Clipboard,
+    image: &image::RgbaImage,
+) -> Result<(), ClipboardError> {
+    let (width, height) = image.dimensions();
+
+    clipboard.set_image(arboard::ImageData {
+        width: width as usize,
+        height: height as usize,
+        bytes: std::borrow::Cow::Borrowed(image.as_raw()),
+    })?;
+
+    Ok(())
+}
@@ -1,27 +1,446 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
 use crate::{document::Document, tex::TexString};
 
 use self::state::{Loaded, Unloaded};
 
+/// Image format the rendered output is encoded into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RenderFormat {
+    #[default]
+    Png,
+    Jpeg {
+        /// Encoder quality in `1..=100`.
+        quality: u8,
+    },
+    Webp,
+}
+
+impl RenderFormat {
+    /// File extension conventionally used for the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RenderFormat::Png => "png",
+            RenderFormat::Jpeg { .. } => "jpg",
+            RenderFormat::Webp => "webp",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            RenderFormat::Png => "image/png",
+            RenderFormat::Jpeg { .. } => "image/jpeg",
+            RenderFormat::Webp => "image/webp",
+        }
+    }
+}
+
+/// Inline `data` as a `data:<mime>;base64,...` URI, e.g. for embedding into HTML.
+pub fn to_data_uri(data: &[u8], format: RenderFormat) -> String {
+    use base64::Engine;
+
+    format!(
+        "data:{};base64,{}",
+        format.mime_type(),
+        base64::engine::general_purpose::STANDARD.encode(data)
+    )
+}
+
+/// Page background the formula is rasterized onto, independent of the text colour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Background {
+    #[default]
+    Transparent,
+    /// Straight (non-premultiplied) RGBA.
+    Solid(u8, u8, u8, u8),
+}
+
+/// How large the rendered output is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Resolution {
+    /// Multiplier passed to `dvisvgm --scale`.
+    Scale(f32),
+    /// Target dots per inch, for predictable pixel sizes in print-ready output.
+    Dpi(f32),
+}
+
+impl Default for Resolution {
+    fn default() -> Self {
+        Self::Scale(2.0)
+    }
+}
+
+/// External programs the native backend turns TeX into SVG with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Toolchain {
+    /// `pdflatex` in DVI mode, then `dvisvgm`.
+    #[default]
+    LatexDvi,
+    /// `pdflatex` producing a PDF, then `pdf2svg`. Slower, but allows including raster images
+    /// such as PNGs with `\includegraphics`, which DVI can't hold.
+    Pdflatex,
+}
+
+/// Fixed output size the rasterized formula is scaled to, preserving its aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Fit {
+    /// Width in pixels, before [`RenderOptions::margin`] is added.
+    Width(u32),
+    /// Height in pixels, before [`RenderOptions::margin`] is added.
+    Height(u32),
+}
+
+/// Rotation or mirroring applied to the finished image, see [`RenderOptions::set_transform`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OutputTransform {
+    /// Rotate 90 degrees clockwise, swapping width and height.
+    Rotate90,
+    /// Rotate 180 degrees.
+    Rotate180,
+    /// Rotate 270 degrees clockwise, swapping width and height.
+    Rotate270,
+    /// Mirror left to right.
+    FlipH,
+    /// Mirror top to bottom.
+    FlipV,
+}
+
+/// Colour filter applied to the rasterized image before it is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ImageFilter {
+    /// Desaturate, keeping alpha.
+    Grayscale,
+    /// 1-bit monochrome: luma at or above the threshold becomes white, the rest black.
+    Threshold(u8),
+}
+
 #[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct RenderOptions {
-    scale: Option<f32>,
+    resolution: Option<Resolution>,
     margin: Option<f32>,
+    format: RenderFormat,
+    timeout: Option<Duration>,
+    background: Background,
+    auto_crop: bool,
+    ghostscript_lib: Option<PathBuf>,
+    embed_source: bool,
+    embed_srgb: bool,
+    max_retries: u32,
+    fit: Option<Fit>,
+    filter: Option<ImageFilter>,
+    supersample: Option<u32>,
+    matte: Option<(u8, u8, u8)>,
+    toolchain: Toolchain,
+    debug_bbox: bool,
+    job_name: Option<String>,
+    transform: Option<OutputTransform>,
+    deterministic: bool,
 }
 
 impl RenderOptions {
+    /// Bounds [`RenderOptions::scale`] and [`RenderOptions::zoom`] are clamped to, keeping
+    /// absurd values from producing empty images or exhausting memory.
+    pub const SCALE_RANGE: std::ops::RangeInclusive<f32> = 0.1..=50.0;
+
+    /// Factors [`RenderOptions::set_supersample`] accepts, larger ones multiply the pixmap size
+    /// beyond any benefit.
+    pub const SUPERSAMPLE_RANGE: std::ops::RangeInclusive<u32> = 1..=8;
+
+    /// [`RenderOptions::job_name`] unless another one is set.
+    pub const DEFAULT_JOB_NAME: &'static str = "texput";
+
     pub fn new(scale: Option<f32>, margin: Option<f32>) -> Self {
-        Self { scale, margin }
+        Self {
+            resolution: scale.map(Resolution::Scale),
+            margin,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_format(&mut self, format: RenderFormat) -> &mut Self {
+        self.format = format;
+        self
+    }
+
+    pub fn format(&self) -> RenderFormat {
+        self.format
+    }
+
+    /// Limit on each external program the backend runs, after which it is killed. `None` (the
+    /// default) or a zero duration lets programs take as long as they need, e.g. for `pdflatex`
+    /// generating formats and fonts on its first run.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.filter(|timeout| !timeout.is_zero())
+    }
+
+    /// How often a program that hit [`RenderOptions::timeout`] is run again, doubling the
+    /// timeout each time. Defaults to `0`, has no effect without a timeout.
+    pub fn set_max_retries(&mut self, max_retries: u32) -> &mut Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    pub fn set_background(&mut self, background: Background) -> &mut Self {
+        self.background = background;
+        self
+    }
+
+    pub fn background(&self) -> Background {
+        self.background
+    }
+
+    /// Crop the rasterized image to its content before [`RenderOptions::margin`] is added,
+    /// independent of how tightly the backend's toolchain already crops.
+    pub fn set_auto_crop(&mut self, auto_crop: bool) -> &mut Self {
+        self.auto_crop = auto_crop;
+        self
+    }
+
+    pub fn auto_crop(&self) -> bool {
+        self.auto_crop
+    }
+
+    pub fn set_resolution(&mut self, resolution: Resolution) -> &mut Self {
+        self.resolution = Some(resolution);
+        self
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution.unwrap_or_default()
+    }
+
+    /// Ghostscript shared library handed to `dvisvgm` through `LIBGS`. When unset, it is looked
+    /// up in a few well-known locations and `dvisvgm`'s own default is used if none exists.
+    pub fn set_ghostscript_lib(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.ghostscript_lib = path;
+        self
+    }
+
+    pub fn ghostscript_lib(&self) -> Option<&PathBuf> {
+        self.ghostscript_lib.as_ref()
+    }
+
+    /// Store the document's TeX in a `Source` `tEXt` chunk of PNG output, to be recovered with
+    /// [`post_process::read_embedded_source`]. Ignored for other formats.
+    pub fn set_embed_source(&mut self, embed_source: bool) -> &mut Self {
+        self.embed_source = embed_source;
+        self
     }
 
+    pub fn embed_source(&self) -> bool {
+        self.embed_source
+    }
+
+    /// Tag PNG output as sRGB with an `sRGB` chunk so colour-managed viewers don't guess.
+    /// Ignored for other formats. Off by default to keep existing output byte for byte.
+    pub fn set_embed_srgb(&mut self, embed_srgb: bool) -> &mut Self {
+        self.embed_srgb = embed_srgb;
+        self
+    }
+
+    pub fn embed_srgb(&self) -> bool {
+        self.embed_srgb
+    }
+
+    /// Scale the rasterized image to an exact width or height. When set, this wins over the
+    /// [`RenderOptions::resolution`] zoom, though [`RenderOptions::scale`] still affects how
+    /// sharp the SVG `dvisvgm` hands over is.
+    pub fn set_fit(&mut self, fit: Option<Fit>) -> &mut Self {
+        self.fit = fit;
+        self
+    }
+
+    pub fn fit(&self) -> Option<Fit> {
+        self.fit
+    }
+
+    /// Filter applied to the whole image, background and margin included.
+    pub fn set_filter(&mut self, filter: Option<ImageFilter>) -> &mut Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn filter(&self) -> Option<ImageFilter> {
+        self.filter
+    }
+
+    /// Rotate or mirror the image as the last step, after cropping, the margin and
+    /// [`RenderOptions::filter`]. The baseline reported by
+    /// [`native::RenderInstanceNative::render_with_metadata`] does not account for it.
+    pub fn set_transform(&mut self, transform: Option<OutputTransform>) -> &mut Self {
+        self.transform = transform;
+        self
+    }
+
+    pub fn transform(&self) -> Option<OutputTransform> {
+        self.transform
+    }
+
+    /// Produce byte-identical output for identical input across runs: `pdflatex` runs with
+    /// `SOURCE_DATE_EPOCH=0` and comments and `<metadata>` are stripped from the SVG, see
+    /// [`post_process::strip_svg_metadata`].
+    pub fn set_deterministic(&mut self, deterministic: bool) -> &mut Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    pub fn deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    /// Rasterize at `supersample` times the output size and downscale with a Lanczos filter,
+    /// for crisper glyph edges at small sizes. `1` (the default) disables supersampling, factors
+    /// outside [`RenderOptions::SUPERSAMPLE_RANGE`] fail [`RenderOptions::validate`].
+    pub fn set_supersample(&mut self, supersample: u32) -> &mut Self {
+        self.supersample = Some(supersample);
+        self
+    }
+
+    pub fn supersample(&self) -> u32 {
+        self.supersample.unwrap_or(1)
+    }
+
+    /// Opaque RGB colour transparent areas are composited over for formats without alpha,
+    /// i.e. JPEG. Defaults to white.
+    pub fn set_matte(&mut self, matte: Option<(u8, u8, u8)>) -> &mut Self {
+        self.matte = matte;
+        self
+    }
+
+    pub fn matte(&self) -> (u8, u8, u8) {
+        self.matte.unwrap_or((255, 255, 255))
+    }
+
+    pub fn set_toolchain(&mut self, toolchain: Toolchain) -> &mut Self {
+        self.toolchain = toolchain;
+        self
+    }
+
+    pub fn toolchain(&self) -> Toolchain {
+        self.toolchain
+    }
+
+    /// Outline the content of the rasterized image with a 1px red rectangle, to debug cropping
+    /// and margins. Off by default.
+    pub fn set_debug_bbox(&mut self, debug_bbox: bool) -> &mut Self {
+        self.debug_bbox = debug_bbox;
+        self
+    }
+
+    pub fn debug_bbox(&self) -> bool {
+        self.debug_bbox
+    }
+
+    /// Base name of the files a render writes to its root directory, `{job}.tex`, `{job}2.svg`
+    /// and so on. Renders sharing a root need different names to not overwrite each other's
+    /// files. `None` keeps [`RenderOptions::DEFAULT_JOB_NAME`].
+    pub fn set_job_name(&mut self, job_name: Option<String>) -> &mut Self {
+        self.job_name = job_name;
+        self
+    }
+
+    pub fn job_name(&self) -> &str {
+        self.job_name.as_deref().unwrap_or(Self::DEFAULT_JOB_NAME)
+    }
+
+    /// Scale handed to `dvisvgm`. Always `1.0` for [`Resolution::Dpi`], which is applied when
+    /// rasterizing instead.
     pub fn scale(&self) -> f32 {
-        self.scale.unwrap_or(2.0)
+        match self.resolution() {
+            Resolution::Scale(scale) => clamp_scale(scale),
+            Resolution::Dpi(_) => 1.0,
+        }
+    }
+
+    /// Zoom applied when rasterizing the SVG, which `usvg` lays out at 96 DPI.
+    pub fn zoom(&self) -> f32 {
+        match self.resolution() {
+            Resolution::Scale(_) => 1.0,
+            Resolution::Dpi(dpi) => clamp_scale(dpi / 96.0),
+        }
     }
 
+    /// Reject a resolution or margin that is NaN or infinite, which no clamping can make sense
+    /// of. Finite scales outside [`RenderOptions::SCALE_RANGE`] are clamped rather than
+    /// rejected. Supersampling factors outside [`RenderOptions::SUPERSAMPLE_RANGE`] are
+    /// rejected.
+    pub fn validate(&self) -> Result<(), RenderError> {
+        let (name, value) = match self.resolution() {
+            Resolution::Scale(scale) => ("scale", scale),
+            Resolution::Dpi(dpi) => ("DPI", dpi),
+        };
+
+        for (name, value) in [(name, value), ("margin", self.margin())] {
+            if !value.is_finite() {
+                return Err(RenderError::InvalidOptions(format!(
+                    "{} must be finite, got {}",
+                    name, value
+                )));
+            }
+        }
+
+        if !Self::SUPERSAMPLE_RANGE.contains(&self.supersample()) {
+            return Err(RenderError::InvalidOptions(format!(
+                "supersample must be in {:?}, got {}",
+                Self::SUPERSAMPLE_RANGE,
+                self.supersample()
+            )));
+        }
+
+        let job_name = self.job_name();
+        if job_name.is_empty()
+            || job_name.starts_with('-')
+            || job_name
+                .chars()
+                .any(|c| c.is_whitespace() || std::path::is_separator(c))
+        {
+            return Err(RenderError::InvalidOptions(format!(
+                "job name must be a plain file name, got {:?}",
+                job_name
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Transparent (or [`RenderOptions::background`]) border added around the image, in pixels.
     pub fn margin(&self) -> f32 {
         self.margin.unwrap_or(4.0)
     }
 }
 
+fn clamp_scale(scale: f32) -> f32 {
+    scale.clamp(
+        *RenderOptions::SCALE_RANGE.start(),
+        *RenderOptions::SCALE_RANGE.end(),
+    )
+}
+
 pub mod state {
     pub struct Unloaded;
     pub struct Loaded;
@@ -33,8 +452,124 @@ pub struct RenderInstance<T: TexString = String, State = Unloaded> {
     _state: std::marker::PhantomData<State>,
 }
 
+/// Rendered image together with metadata about its placement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderResult {
+    /// Encoded image, as returned by [`RenderBackend::render`].
+    pub data: Vec<u8>,
+    /// Distance from the bottom edge of the image up to the baseline of the formula, in pixels.
+    ///
+    /// Meant for `vertical-align: -{baseline_px}px` when placing inline formulas into text.
+    /// `None` if the backend could not determine it.
+    pub baseline_px: Option<f32>,
+    /// Warnings (La)TeX printed while compiling, the render succeeded regardless.
+    pub warnings: Vec<String>,
+    /// Directory the intermediate files such as the `.tex` and `.log` were written to.
+    pub root: PathBuf,
+    /// The (La)TeX source as it was compiled.
+    pub tex: String,
+}
+
+/// Step a render is at, reported to [`native::RenderInstanceNative::on_progress`] as it begins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RenderStage {
+    /// Writing the `.tex` file.
+    WritingTex,
+    /// Running (La)TeX.
+    Compiling,
+    /// Converting the compiled output to SVG.
+    ConvertingSvg,
+    /// Rasterizing and encoding the image.
+    Rasterizing,
+    /// The render finished successfully.
+    Done,
+}
+
+/// Shared flag to abandon a render that has been superseded, e.g. by newer input in a live
+/// preview. Clones refer to the same flag.
+///
+/// Backends check it between steps, so a step already running, such as a compile, finishes
+/// first and its output is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask renders holding this token to stop at their next check.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Errors produced by a [`RenderBackend`].
+#[derive(Debug, thiserror::Error)]
+pub enum RenderError {
+    /// An external program the backend relies on could not be started.
+    #[error("render backend unavailable: `{0}` could not be run")]
+    BackendUnavailable(String),
+    /// (La)TeX failed to produce output. Carries the parsed compiler logs.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("(La)TeX compilation failed")]
+    Compile(Vec<native::LogRecord>),
+    #[error("failed to parse SVG: {0}")]
+    SvgParse(#[from] usvg::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("failed to encode image: {0}")]
+    Encode(String),
+    /// The output has no area, e.g. because the document compiled to an empty page.
+    #[error("rendered image is empty")]
+    EmptyOutput,
+    /// The [`RenderOptions`] or the document's options can't be rendered with, see
+    /// [`RenderOptions::validate`] and [`crate::document::DocumentOptions::validate`].
+    #[error("invalid render options: {0}")]
+    InvalidOptions(String),
+    /// The document uses primitives its sandbox blocks, listed comma-separated, see
+    /// [`crate::document::DocumentOptions::sandbox`].
+    #[error("content uses blocked primitives: {0}")]
+    Unsafe(String),
+    /// The render was abandoned through its [`CancelToken`].
+    #[error("render cancelled")]
+    Cancelled,
+    /// An external program was killed after running longer than [`RenderOptions::timeout`] on
+    /// its last retry.
+    #[error("`{program}` timed out after {timeout:?}")]
+    Timeout { program: String, timeout: Duration },
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl RenderError {
+    /// Map a failure to spawn `program` to [`RenderError::BackendUnavailable`] when it is missing.
+    pub(crate) fn spawn(program: &str, err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => RenderError::BackendUnavailable(program.to_string()),
+            _ => RenderError::Io(err),
+        }
+    }
+}
+
 pub trait RenderBackend {
-    fn render(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+    fn render(&mut self) -> Result<Vec<u8>, RenderError>;
+
+    /// Render into `writer` instead of returning the image. Backends override this to encode
+    /// straight into the writer, the default buffers through [`RenderBackend::render`].
+    fn render_to<W: std::io::Write>(&mut self, writer: &mut W) -> Result<(), RenderError>
+    where
+        Self: Sized,
+    {
+        writer.write_all(&self.render()?)?;
+        Ok(())
+    }
+
+    /// Render to the SVG produced by `dvisvgm`, skipping rasterization entirely.
+    fn render_svg(&mut self) -> Result<Vec<u8>, RenderError>;
 }
 
 impl<T: TexString> RenderInstance<T> {
@@ -42,7 +577,7 @@ impl<T: TexString> RenderInstance<T> {
         RenderInstance::<T, Unloaded> {
             options: RenderOptions::default(),
             last_document: None,
-            _state: std::marker::PhantomData::default(),
+            _state: std::marker::PhantomData,
         }
     }
 
@@ -50,7 +585,7 @@ impl<T: TexString> RenderInstance<T> {
         Self {
             options,
             last_document: None,
-            _state: std::marker::PhantomData::default(),
+            _state: std::marker::PhantomData,
         }
     }
 
@@ -58,14 +593,871 @@ impl<T: TexString> RenderInstance<T> {
         RenderInstance::<T, Loaded> {
             options: self.options,
             last_document: Some(document),
-            _state: std::marker::PhantomData::default(),
+            _state: std::marker::PhantomData,
         }
     }
 }
 
+impl<T: TexString> Default for RenderInstance<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: TexString> RenderInstance<T, Loaded> {
+    /// Document passed to [`RenderInstance::load`].
+    ///
+    /// Only callable once a document is loaded:
+    ///
+    /// ```compile_fail
+    /// use teximex::render::RenderInstance;
+    ///
+    /// let instance = RenderInstance::<String>::new();
+    /// instance.document();
+    /// ```
     pub fn document(&self) -> &Document<T> {
-        &self.last_document.as_ref().unwrap()
+        self.last_document.as_ref().unwrap()
+    }
+}
+
+pub mod post_process {
+    use std::io::{Cursor, Write};
+
+    use image::ImageEncoder;
+
+    use crate::{document::Document, tex::TexString};
+
+    use super::{
+        Background, Fit, ImageFilter, OutputTransform, RenderError, RenderFormat, RenderOptions,
+    };
+
+    /// Linear model used by [`Document::estimate_output_size_kb`].
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct SizeModel {
+        /// Size of an (almost) empty render.
+        pub base_kb: f32,
+        /// Size added per unit of complexity at scale `1.0`.
+        pub scale_factor: f32,
+        /// Size added per `\usepackage` in the preamble.
+        pub import_kb: f32,
+    }
+
+    /// Calibrated against PNG renders of the default document at scales `1.0..=10.0`.
+    pub const SIZE_MODEL: SizeModel = SizeModel {
+        base_kb: 1.0,
+        scale_factor: 0.15,
+        import_kb: 0.5,
+    };
+
+    /// Rasterize `tree` at its original size onto the configured background.
+    ///
+    /// Fails with [`RenderError::EmptyOutput`] when the tree has no area to draw onto.
+    pub fn rasterize(
+        tree: &usvg::Tree,
+        options: &RenderOptions,
+    ) -> Result<tiny_skia::Pixmap, RenderError> {
+        options.validate()?;
+
+        let fit_to = match (options.fit(), options.zoom()) {
+            (Some(Fit::Width(width)), _) => usvg::FitTo::Width(width),
+            (Some(Fit::Height(height)), _) => usvg::FitTo::Height(height),
+            (None, 1.0) => usvg::FitTo::Original,
+            (None, zoom) => usvg::FitTo::Zoom(zoom),
+        };
+
+        let pixmap_size = fit_to
+            .fit_to(tree.size.to_screen_size())
+            .ok_or(RenderError::EmptyOutput)?;
+
+        let supersample = options.supersample();
+        let fit_to = match supersample {
+            1 => fit_to,
+            n => {
+                let too_large =
+                    || RenderError::InvalidOptions("supersampled image is too large".into());
+                usvg::FitTo::Size(
+                    pixmap_size.width().checked_mul(n).ok_or_else(too_large)?,
+                    pixmap_size.height().checked_mul(n).ok_or_else(too_large)?,
+                )
+            }
+        };
+        let render_size = fit_to
+            .fit_to(tree.size.to_screen_size())
+            .ok_or(RenderError::EmptyOutput)?;
+
+        let mut pixmap = tiny_skia::Pixmap::new(render_size.width(), render_size.height())
+            .ok_or(RenderError::EmptyOutput)?;
+
+        if let Background::Solid(r, g, b, a) = options.background() {
+            pixmap.fill(tiny_skia::Color::from_rgba8(r, g, b, a));
+        }
+
+        resvg::render(
+            tree,
+            fit_to,
+            tiny_skia::Transform::default(),
+            pixmap.as_mut(),
+        )
+        .ok_or(RenderError::EmptyOutput)?;
+
+        if supersample > 1 {
+            let image = image::imageops::resize(
+                &to_rgba_image(&pixmap),
+                pixmap_size.width(),
+                pixmap_size.height(),
+                image::imageops::FilterType::Lanczos3,
+            );
+            pixmap = from_rgba_image(&image).ok_or(RenderError::EmptyOutput)?;
+        }
+
+        let pixmap = if options.auto_crop() {
+            crop(&pixmap, options.background(), 0)
+        } else {
+            pixmap
+        };
+
+        let pixmap = pad(&pixmap, options.background(), options.margin() as u32);
+
+        let pixmap = match options.filter() {
+            Some(filter) => apply_filter(&pixmap, filter),
+            None => pixmap,
+        };
+
+        let pixmap = if options.debug_bbox() {
+            draw_bbox(&pixmap, options.background())
+        } else {
+            pixmap
+        };
+
+        let pixmap = match options.transform() {
+            Some(transform) => apply_transform(&pixmap, transform),
+            None => pixmap,
+        };
+
+        Ok(match options.format() {
+            RenderFormat::Jpeg { .. } => flatten(&pixmap, options.matte()),
+            _ => pixmap,
+        })
+    }
+
+    /// Apply `filter` to `pixmap`.
+    pub fn apply_filter(pixmap: &tiny_skia::Pixmap, filter: ImageFilter) -> tiny_skia::Pixmap {
+        let mut image = image::DynamicImage::ImageRgba8(to_rgba_image(pixmap))
+            .grayscale()
+            .to_rgba8();
+
+        if let ImageFilter::Threshold(threshold) = filter {
+            for pixel in image.pixels_mut() {
+                let value = if pixel.0[0] >= threshold { 255 } else { 0 };
+                pixel.0[..3].fill(value);
+            }
+        }
+
+        from_rgba_image(&image).unwrap_or_else(|| pixmap.clone())
+    }
+
+    /// Rotate or mirror `pixmap` according to `transform`.
+    pub fn apply_transform(
+        pixmap: &tiny_skia::Pixmap,
+        transform: OutputTransform,
+    ) -> tiny_skia::Pixmap {
+        let image = to_rgba_image(pixmap);
+        let image = match transform {
+            OutputTransform::Rotate90 => image::imageops::rotate90(&image),
+            OutputTransform::Rotate180 => image::imageops::rotate180(&image),
+            OutputTransform::Rotate270 => image::imageops::rotate270(&image),
+            OutputTransform::FlipH => image::imageops::flip_horizontal(&image),
+            OutputTransform::FlipV => image::imageops::flip_vertical(&image),
+        };
+
+        from_rgba_image(&image).unwrap_or_else(|| pixmap.clone())
+    }
+
+    /// Direction the images of a sprite sheet are laid out in.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub enum SheetLayout {
+        /// Side by side, centred vertically.
+        #[default]
+        Horizontal,
+        /// Stacked, centred horizontally.
+        Vertical,
+    }
+
+    /// Where an image was placed on a sprite sheet, in pixels.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct SheetRect {
+        pub x: u32,
+        pub y: u32,
+        pub width: u32,
+        pub height: u32,
+    }
+
+    /// Tile `pixmaps` into a single sheet filled with `background`.
+    ///
+    /// Images are centred on the cross axis rather than aligned on their baselines, which the
+    /// rasterized pixmaps carry no information about.
+    pub fn tile(
+        pixmaps: &[tiny_skia::Pixmap],
+        layout: SheetLayout,
+        background: Background,
+    ) -> Result<(tiny_skia::Pixmap, Vec<SheetRect>), RenderError> {
+        let (main, cross) = pixmaps
+            .iter()
+            .fold((0, 0), |(main, cross), pixmap| match layout {
+                SheetLayout::Horizontal => (main + pixmap.width(), cross.max(pixmap.height())),
+                SheetLayout::Vertical => (main + pixmap.height(), cross.max(pixmap.width())),
+            });
+
+        let (width, height) = match layout {
+            SheetLayout::Horizontal => (main, cross),
+            SheetLayout::Vertical => (cross, main),
+        };
+        let mut sheet = tiny_skia::Pixmap::new(width, height).ok_or(RenderError::EmptyOutput)?;
+
+        if let Background::Solid(r, g, b, a) = background {
+            sheet.fill(tiny_skia::Color::from_rgba8(r, g, b, a));
+        }
+
+        let mut rects = Vec::with_capacity(pixmaps.len());
+        let mut offset = 0;
+
+        for pixmap in pixmaps {
+            let rect = match layout {
+                SheetLayout::Horizontal => SheetRect {
+                    x: offset,
+                    y: (cross - pixmap.height()) / 2,
+                    width: pixmap.width(),
+                    height: pixmap.height(),
+                },
+                SheetLayout::Vertical => SheetRect {
+                    x: (cross - pixmap.width()) / 2,
+                    y: offset,
+                    width: pixmap.width(),
+                    height: pixmap.height(),
+                },
+            };
+
+            sheet.draw_pixmap(
+                rect.x as i32,
+                rect.y as i32,
+                pixmap.as_ref(),
+                &tiny_skia::PixmapPaint::default(),
+                tiny_skia::Transform::identity(),
+                None,
+            );
+
+            offset += match layout {
+                SheetLayout::Horizontal => rect.width,
+                SheetLayout::Vertical => rect.height,
+            };
+            rects.push(rect);
+        }
+
+        Ok((sheet, rects))
+    }
+
+    /// Surround `pixmap` with a `margin` pixels wide border of `background`.
+    pub fn pad(
+        pixmap: &tiny_skia::Pixmap,
+        background: Background,
+        margin: u32,
+    ) -> tiny_skia::Pixmap {
+        if margin == 0 {
+            return pixmap.clone();
+        }
+
+        let Some(mut padded) =
+            tiny_skia::Pixmap::new(pixmap.width() + 2 * margin, pixmap.height() + 2 * margin)
+        else {
+            return pixmap.clone();
+        };
+
+        if let Background::Solid(r, g, b, a) = background {
+            padded.fill(tiny_skia::Color::from_rgba8(r, g, b, a));
+        }
+
+        let paint = tiny_skia::PixmapPaint {
+            blend_mode: tiny_skia::BlendMode::Source,
+            ..Default::default()
+        };
+        padded.draw_pixmap(
+            margin as i32,
+            margin as i32,
+            pixmap.as_ref(),
+            &paint,
+            tiny_skia::Transform::identity(),
+            None,
+        );
+
+        padded
+    }
+
+    /// Bounding box `(left, top, right, bottom)` (inclusive) of the pixels that differ from
+    /// `background`, `None` if there are none.
+    pub fn content_bounds(
+        pixmap: &tiny_skia::Pixmap,
+        background: Background,
+    ) -> Option<(u32, u32, u32, u32)> {
+        let background = match background {
+            Background::Transparent => tiny_skia::ColorU8::from_rgba(0, 0, 0, 0),
+            Background::Solid(r, g, b, a) => tiny_skia::ColorU8::from_rgba(r, g, b, a),
+        }
+        .premultiply();
+
+        let width = pixmap.width();
+        let mut bounds: Option<(u32, u32, u32, u32)> = None;
+
+        for (i, pixel) in pixmap.pixels().iter().enumerate() {
+            if *pixel == background {
+                continue;
+            }
+
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            bounds = Some(match bounds {
+                None => (x, y, x, y),
+                Some((l, t, r, b)) => (l.min(x), t.min(y), r.max(x), b.max(y)),
+            });
+        }
+
+        bounds
+    }
+
+    /// Outline the [`content_bounds`] of `pixmap` with a 1px red rectangle just outside them,
+    /// clamped to the image. Pixmaps without any content are returned unchanged.
+    pub fn draw_bbox(pixmap: &tiny_skia::Pixmap, background: Background) -> tiny_skia::Pixmap {
+        let mut outlined = pixmap.clone();
+        let Some((left, top, right, bottom)) = content_bounds(pixmap, background) else {
+            return outlined;
+        };
+
+        let left = left.saturating_sub(1);
+        let top = top.saturating_sub(1);
+        let right = (right + 1).min(pixmap.width() - 1);
+        let bottom = (bottom + 1).min(pixmap.height() - 1);
+
+        let red = tiny_skia::ColorU8::from_rgba(255, 0, 0, 255).premultiply();
+        let width = pixmap.width();
+        for (i, pixel) in outlined.pixels_mut().iter_mut().enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            let vertical = (x == left || x == right) && (top..=bottom).contains(&y);
+            let horizontal = (y == top || y == bottom) && (left..=right).contains(&x);
+            if vertical || horizontal {
+                *pixel = red;
+            }
+        }
+
+        outlined
+    }
+
+    /// Crop `pixmap` to its content plus `margin` pixels on each side, clamped to the original
+    /// size. Pixmaps without any content are returned unchanged.
+    pub fn crop(
+        pixmap: &tiny_skia::Pixmap,
+        background: Background,
+        margin: u32,
+    ) -> tiny_skia::Pixmap {
+        let Some((left, top, right, bottom)) = content_bounds(pixmap, background) else {
+            return pixmap.clone();
+        };
+
+        let left = left.saturating_sub(margin);
+        let top = top.saturating_sub(margin);
+        let right = (right + margin).min(pixmap.width() - 1);
+        let bottom = (bottom + margin).min(pixmap.height() - 1);
+
+        tiny_skia::IntRect::from_xywh(left as i32, top as i32, right - left + 1, bottom - top + 1)
+            .and_then(|rect| pixmap.clone_rect(rect))
+            .unwrap_or_else(|| pixmap.clone())
+    }
+
+    /// Convert a premultiplied [`tiny_skia::Pixmap`] into a straight-alpha [`image::RgbaImage`].
+    pub fn to_rgba_image(pixmap: &tiny_skia::Pixmap) -> image::RgbaImage {
+        let mut data = Vec::with_capacity(pixmap.data().len());
+        for pixel in pixmap.pixels() {
+            let c = pixel.demultiply();
+            data.extend_from_slice(&[c.red(), c.green(), c.blue(), c.alpha()]);
+        }
+
+        image::RgbaImage::from_raw(pixmap.width(), pixmap.height(), data)
+            .expect("pixmap data matches its dimensions")
+    }
+
+    /// Composite `pixmap` over the opaque `matte` colour, for formats without alpha.
+    pub fn flatten(pixmap: &tiny_skia::Pixmap, matte: (u8, u8, u8)) -> tiny_skia::Pixmap {
+        let (r, g, b) = matte;
+        let mut flat = pixmap.clone();
+        flat.fill(tiny_skia::Color::from_rgba8(r, g, b, 255));
+        flat.draw_pixmap(
+            0,
+            0,
+            pixmap.as_ref(),
+            &tiny_skia::PixmapPaint::default(),
+            tiny_skia::Transform::identity(),
+            None,
+        );
+
+        flat
+    }
+
+    /// Convert a straight-alpha [`image::RgbaImage`] into a premultiplied [`tiny_skia::Pixmap`],
+    /// `None` if it is empty.
+    pub fn from_rgba_image(image: &image::RgbaImage) -> Option<tiny_skia::Pixmap> {
+        let mut pixmap = tiny_skia::Pixmap::new(image.width(), image.height())?;
+        for (dst, src) in pixmap.pixels_mut().iter_mut().zip(image.pixels()) {
+            let [r, g, b, a] = src.0;
+            *dst = tiny_skia::ColorU8::from_rgba(r, g, b, a).premultiply();
+        }
+
+        Some(pixmap)
+    }
+
+    /// Encode `pixmap` into `format`.
+    pub fn encode(
+        pixmap: &tiny_skia::Pixmap,
+        format: RenderFormat,
+    ) -> Result<Vec<u8>, RenderError> {
+        let mut out = Vec::new();
+        encode_to(pixmap, format, &mut out)?;
+        Ok(out)
+    }
+
+    /// Encode `pixmap` into `format`, writing straight to `writer`.
+    ///
+    /// PNG and JPEG are streamed, WebP is encoded in memory first since its encoder needs to
+    /// seek. JPEG has no alpha, which is dropped: [`flatten`] the pixmap onto a matte first,
+    /// [`rasterize`] already does for [`RenderFormat::Jpeg`] using [`RenderOptions::matte`].
+    pub fn encode_to<W: Write>(
+        pixmap: &tiny_skia::Pixmap,
+        format: RenderFormat,
+        writer: &mut W,
+    ) -> Result<(), RenderError> {
+        let image = to_rgba_image(pixmap);
+
+        match format {
+            RenderFormat::Png => image::codecs::png::PngEncoder::new(writer).write_image(
+                image.as_raw(),
+                image.width(),
+                image.height(),
+                image::ColorType::Rgba8,
+            ),
+            RenderFormat::Jpeg { quality } => {
+                let image = image::DynamicImage::ImageRgba8(image).to_rgb8();
+                image::codecs::jpeg::JpegEncoder::new_with_quality(writer, quality)
+                    .encode_image(&image)
+            }
+            RenderFormat::Webp => {
+                let mut out = Cursor::new(Vec::new());
+                image::DynamicImage::ImageRgba8(image)
+                    .write_to(&mut out, image::ImageOutputFormat::WebP)
+                    .map_err(|e| RenderError::Encode(e.to_string()))?;
+                return Ok(writer.write_all(out.get_ref())?);
+            }
+        }
+        .map_err(|e| RenderError::Encode(e.to_string()))
+    }
+
+    const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    /// Keyword of the `tEXt` chunk written by [`embed_source`].
+    const SOURCE_KEYWORD: &[u8] = b"Source";
+
+    /// CRC-32 as used by PNG chunks.
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in bytes {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    /// Iterate over the `(type, data)` of each chunk in `png`, stopping at the first malformed one.
+    fn png_chunks(png: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+        let mut rest = png.strip_prefix(PNG_SIGNATURE).unwrap_or(&[]);
+
+        std::iter::from_fn(move || {
+            let len = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+            let kind: [u8; 4] = rest.get(4..8)?.try_into().ok()?;
+            let data = rest.get(8..8 + len)?;
+            rest = rest.get(8 + len + 4..)?;
+            Some((kind, data))
+        })
+    }
+
+    /// Insert a chunk of `kind` holding `data` right after the `IHDR` chunk of `png`. Data that
+    /// is not a PNG is returned as is.
+    fn insert_chunk(png: &[u8], kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        // Signature plus the 13 byte IHDR chunk with its length, type and CRC.
+        let ihdr_end = PNG_SIGNATURE.len() + 4 + 4 + 13 + 4;
+        if !png.starts_with(PNG_SIGNATURE) || png.len() < ihdr_end {
+            return png.to_vec();
+        }
+
+        let mut chunk = Vec::with_capacity(kind.len() + data.len());
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+
+        let mut out = Vec::with_capacity(png.len() + chunk.len() + 8);
+        out.extend_from_slice(&png[..ihdr_end]);
+        out.extend_from_slice(&((chunk.len() - 4) as u32).to_be_bytes());
+        out.extend_from_slice(&chunk);
+        out.extend_from_slice(&crc32(&chunk).to_be_bytes());
+        out.extend_from_slice(&png[ihdr_end..]);
+        out
+    }
+
+    /// Insert a `Source` `tEXt` chunk holding `source` right after the `IHDR` chunk of `png`.
+    ///
+    /// `tEXt` is nominally Latin-1, the source is stored as UTF-8 bytes regardless so that it
+    /// round-trips through [`read_embedded_source`]. Data that is not a PNG is returned as is.
+    pub fn embed_source(png: &[u8], source: &str) -> Vec<u8> {
+        let mut text = Vec::with_capacity(SOURCE_KEYWORD.len() + 1 + source.len());
+        text.extend_from_slice(SOURCE_KEYWORD);
+        text.push(0);
+        text.extend_from_slice(source.as_bytes());
+
+        insert_chunk(png, b"tEXt", &text)
+    }
+
+    /// Insert an `sRGB` chunk with the perceptual rendering intent right after the `IHDR`
+    /// chunk of `png`. Data that is not a PNG is returned as is.
+    pub fn embed_srgb(png: &[u8]) -> Vec<u8> {
+        insert_chunk(png, b"sRGB", &[0])
+    }
+
+    /// Remove XML comments and `<metadata>` elements from `svg`, where converters note their
+    /// version and creation dates.
+    pub fn strip_svg_metadata(svg: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(svg.len());
+        let mut rest = svg;
+
+        loop {
+            let comment = find(rest, b"<!--").map(|start| (start, &b"-->"[..]));
+            let metadata = find(rest, b"<metadata").map(|start| (start, &b"</metadata>"[..]));
+            let next = match (comment, metadata) {
+                (Some(c), Some(m)) => Some(if c.0 < m.0 { c } else { m }),
+                (c, m) => c.or(m),
+            };
+
+            let Some((start, end)) = next else {
+                out.extend_from_slice(rest);
+                return out;
+            };
+
+            out.extend_from_slice(&rest[..start]);
+            rest = match find(&rest[start..], end) {
+                Some(len) => &rest[start + len + end.len()..],
+                None => &[],
+            };
+        }
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    }
+
+    /// `(width, height)` from the `IHDR` chunk of `png`, without decoding it.
+    pub fn png_dimensions(png: &[u8]) -> Option<(u32, u32)> {
+        let (kind, data) = png_chunks(png).next()?;
+        if &kind != b"IHDR" {
+            return None;
+        }
+
+        let width = u32::from_be_bytes(data.get(..4)?.try_into().ok()?);
+        let height = u32::from_be_bytes(data.get(4..8)?.try_into().ok()?);
+        Some((width, height))
+    }
+
+    /// The TeX stored by [`RenderOptions::set_embed_source`], if `png` carries any.
+    pub fn read_embedded_source(png: &[u8]) -> Option<String> {
+        png_chunks(png)
+            .filter(|(kind, _)| kind == b"tEXt")
+            .find_map(|(_, data)| {
+                let text = data.strip_prefix(SOURCE_KEYWORD)?.strip_prefix(&[0])?;
+                String::from_utf8(text.to_vec()).ok()
+            })
+    }
+
+    /// Rough measure of how much ink a piece of TeX puts on the page.
+    fn complexity(tex: &str) -> usize {
+        let mut complexity = 0;
+        let mut chars = tex.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    complexity += 1;
+                    while chars.next_if(|c| c.is_ascii_alphabetic()).is_some() {}
+                }
+                '^' | '_' | '{' => complexity += 1,
+                c if c.is_alphanumeric() => complexity += 1,
+                _ => {}
+            }
+        }
+
+        complexity
+    }
+
+    impl<T: TexString> Document<T> {
+        /// Estimate the size of the rendered image in kilobytes.
+        ///
+        /// This is a heuristic based on [`SIZE_MODEL`], not a guarantee.
+        pub fn estimate_output_size_kb(&self, options: &RenderOptions) -> u32 {
+            let complexity = complexity(&self.content().to_tex()) as f32;
+            let imports = self.options().preamble.matches(r#"\usepackage"#).count() as f32;
+            let area = (options.scale() * options.zoom()).powi(2);
+
+            let kb = SIZE_MODEL.base_kb
+                + complexity * SIZE_MODEL.scale_factor * area
+                + imports * SIZE_MODEL.import_kb;
+
+            kb.ceil() as u32
+        }
+    }
+}
+
+pub mod cache {
+    use std::{
+        collections::{HashMap, VecDeque},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use sha2::{Digest, Sha256};
+
+    use super::RenderOptions;
+
+    /// Cache of rendered images keyed by a hash of the document and the options that affect the
+    /// output, either on disk or in memory.
+    ///
+    /// Clones share the same entries and [`RenderCache::stats`].
+    #[derive(Debug, Clone)]
+    pub struct RenderCache {
+        store: Store,
+        hits: Arc<AtomicU64>,
+        misses: Arc<AtomicU64>,
+    }
+
+    #[derive(Debug, Clone)]
+    enum Store {
+        Dir(PathBuf),
+        Memory(Arc<Mutex<MemoryStore>>),
+    }
+
+    /// SHA-256 of a document and the options that affect its output, see [`RenderCache::key`].
+    ///
+    /// Displays as lowercase hex, which is also the entry's file name in a directory cache.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct CacheKey([u8; 32]);
+
+    impl std::fmt::Display for CacheKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+        }
+    }
+
+    /// Hit and miss counts of a [`RenderCache`], see [`RenderCache::stats`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct CacheStats {
+        pub hits: u64,
+        pub misses: u64,
+    }
+
+    /// Entries of an in-memory cache, evicted least recently used first once their total size
+    /// exceeds `capacity` bytes.
+    #[derive(Debug, Default)]
+    struct MemoryStore {
+        capacity: usize,
+        size: usize,
+        entries: HashMap<CacheKey, Vec<u8>>,
+        /// Keys from least to most recently used.
+        order: VecDeque<CacheKey>,
+    }
+
+    impl MemoryStore {
+        fn get(&mut self, key: CacheKey) -> Option<Vec<u8>> {
+            let data = self.entries.get(&key)?.clone();
+            self.touch(key);
+            Some(data)
+        }
+
+        fn insert(&mut self, key: CacheKey, data: &[u8]) {
+            self.remove(key);
+            if data.len() > self.capacity {
+                return;
+            }
+
+            self.size += data.len();
+            self.entries.insert(key, data.to_vec());
+            self.order.push_back(key);
+
+            while self.size > self.capacity {
+                match self.order.front() {
+                    Some(&oldest) => self.remove(oldest),
+                    None => break,
+                }
+            }
+        }
+
+        fn remove(&mut self, key: CacheKey) {
+            if let Some(data) = self.entries.remove(&key) {
+                self.size -= data.len();
+                self.order.retain(|k| *k != key);
+            }
+        }
+
+        fn touch(&mut self, key: CacheKey) {
+            self.order.retain(|k| *k != key);
+            self.order.push_back(key);
+        }
+
+        fn clear(&mut self) {
+            self.entries.clear();
+            self.order.clear();
+            self.size = 0;
+        }
+    }
+
+    impl RenderCache {
+        fn new(store: Store) -> Self {
+            Self {
+                store,
+                hits: Arc::new(AtomicU64::new(0)),
+                misses: Arc::new(AtomicU64::new(0)),
+            }
+        }
+
+        pub fn with_dir<P: Into<PathBuf>>(dir: P) -> Self {
+            Self::new(Store::Dir(dir.into()))
+        }
+
+        /// Cache in process memory holding up to `capacity` bytes of images, evicting the least
+        /// recently used ones first. Images larger than `capacity` are not cached.
+        pub fn in_memory(capacity: usize) -> Self {
+            Self::new(Store::Memory(Arc::new(Mutex::new(MemoryStore {
+                capacity,
+                ..Default::default()
+            }))))
+        }
+
+        /// The directory entries are stored in, `None` for [`RenderCache::in_memory`] caches.
+        pub fn dir(&self) -> Option<&PathBuf> {
+            match &self.store {
+                Store::Dir(dir) => Some(dir),
+                Store::Memory(_) => None,
+            }
+        }
+
+        /// How often [`RenderCache::get`] found an entry and how often it did not.
+        pub fn stats(&self) -> CacheStats {
+            CacheStats {
+                hits: self.hits.load(Ordering::Relaxed),
+                misses: self.misses.load(Ordering::Relaxed),
+            }
+        }
+
+        /// Cache key for a document rendered as `tex` with `options`.
+        ///
+        /// Hashes a fixed encoding of its inputs, so keys stay the same across Rust releases and
+        /// platforms and a directory cache can be kept between them.
+        pub fn key(tex: &str, options: &RenderOptions) -> CacheKey {
+            let mut hasher = Sha256::new();
+            hasher.update((tex.len() as u64).to_le_bytes());
+            hasher.update(tex);
+
+            // One `name=value` line per option, floats as their bits, the rest as `Debug`.
+            let fields = [
+                format!("scale={:08x}", options.scale().to_bits()),
+                format!("zoom={:08x}", options.zoom().to_bits()),
+                format!("margin={:08x}", options.margin().to_bits()),
+                format!("auto_crop={}", options.auto_crop()),
+                format!("format={:?}", options.format()),
+                format!("background={:?}", options.background()),
+                format!("embed_source={}", options.embed_source()),
+                format!("embed_srgb={}", options.embed_srgb()),
+                format!("matte={:?}", options.matte()),
+                format!("toolchain={:?}", options.toolchain()),
+                format!("fit={:?}", options.fit()),
+                format!("filter={:?}", options.filter()),
+                format!("supersample={}", options.supersample()),
+                format!("debug_bbox={}", options.debug_bbox()),
+                format!("transform={:?}", options.transform()),
+            ];
+            for field in fields {
+                hasher.update(field);
+                hasher.update(b"\n");
+            }
+
+            CacheKey(hasher.finalize().into())
+        }
+
+        fn path(dir: &Path, key: CacheKey) -> PathBuf {
+            dir.join(key.to_string())
+        }
+
+        pub fn get(&self, key: CacheKey) -> Option<Vec<u8>> {
+            let data = match &self.store {
+                Store::Dir(dir) => std::fs::read(Self::path(dir, key)).ok(),
+                Store::Memory(store) => store.lock().unwrap().get(key),
+            };
+
+            let counter = if data.is_some() {
+                &self.hits
+            } else {
+                &self.misses
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+
+            data
+        }
+
+        pub fn insert(&self, key: CacheKey, data: &[u8]) -> Result<(), std::io::Error> {
+            match &self.store {
+                Store::Dir(dir) => {
+                    std::fs::create_dir_all(dir)?;
+                    std::fs::write(Self::path(dir, key), data)
+                }
+                Store::Memory(store) => {
+                    store.lock().unwrap().insert(key, data);
+                    Ok(())
+                }
+            }
+        }
+
+        /// Remove every cached entry, keeping the directory itself.
+        pub fn clear(&self) -> Result<(), std::io::Error> {
+            let dir = match &self.store {
+                Store::Dir(dir) => dir,
+                Store::Memory(store) => {
+                    store.lock().unwrap().clear();
+                    return Ok(());
+                }
+            };
+
+            if !dir.exists() {
+                return Ok(());
+            }
+
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_file() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+
+            Ok(())
+        }
     }
 }
 
@@ -76,8 +1468,11 @@ pub mod log {
     }
 }
 
+/// Backend spawning a local `pdflatex` and `dvisvgm`. Not available on `wasm32`, where only the
+/// TeX building and post-processing parts of the crate are usable.
+#[cfg(not(target_arch = "wasm32"))]
 pub mod native {
-    use std::{fs::File, io::{Write, Stdout}, path::PathBuf, process::{Command, Stdio}, fmt::Display};
+    use std::{fs::File, io::Write, path::{Path, PathBuf}, process::{Command, Output, Stdio}, fmt::Display, time::{Duration, Instant}};
 /*
     use tectonic::{
         config,
@@ -86,9 +1481,17 @@ pub mod native {
     };
 */
 
-    use crate::tex::TexString;
+    use rayon::prelude::*;
+
+    use crate::{document::Document, tex::TexString};
 
-    use super::{state::Loaded, RenderBackend, RenderInstance};
+    use super::{
+        cache::RenderCache,
+        post_process::{self, SheetLayout, SheetRect},
+        state::Loaded,
+        CancelToken, RenderBackend, RenderError, RenderFormat, RenderInstance, RenderOptions,
+        RenderResult, RenderStage, Resolution, Toolchain,
+    };
 
     #[derive(Debug, Clone)]
     pub struct PdflatexLogRecord {
@@ -97,89 +1500,864 @@ pub mod native {
         content: String,
     }
 
-    #[derive(Debug, Clone)]
-    pub enum LogRecord {
-        Pdflatex(Vec<PdflatexLogRecord>),
-    }
+    #[derive(Debug, Clone)]
+    pub enum LogRecord {
+        Pdflatex(Vec<PdflatexLogRecord>),
+        /// Standard error of an external program that exited unsuccessfully.
+        Stderr {
+            program: String,
+            output: String,
+        },
+        /// An external program timed out and is run again with a longer `timeout`.
+        Retry {
+            program: String,
+            timeout: Duration,
+        },
+        /// A `pdflatex` warning, e.g. an undefined reference or an overfull box. Compilation
+        /// carries on past these.
+        Warning(String),
+        /// How long a stage of the render took: `compile`, `svg`, `raster` or `encode`.
+        /// External programs are timed including retries.
+        Timing {
+            stage: &'static str,
+            duration: Duration,
+        },
+    }
+
+    impl LogRecord {
+        /// Whether this is a [`LogRecord::Warning`].
+        pub fn is_warning(&self) -> bool {
+            matches!(self, LogRecord::Warning(_))
+        }
+
+        /// Whether this records a failure, i.e. `pdflatex` errors or the standard error of a
+        /// program that exited unsuccessfully.
+        pub fn is_error(&self) -> bool {
+            match self {
+                LogRecord::Pdflatex(records) => !records.is_empty(),
+                LogRecord::Stderr { .. } => true,
+                LogRecord::Retry { .. } | LogRecord::Warning(_) | LogRecord::Timing { .. } => false,
+            }
+        }
+    }
+
+    impl Display for PdflatexLogRecord {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "{}: {} \u{2014} {}",
+                self.line,
+                self.info.trim(),
+                self.content.trim()
+            )
+        }
+    }
+
+    impl Display for LogRecord {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                LogRecord::Pdflatex(records) => {
+                    for (i, record) in records.iter().enumerate() {
+                        if i > 0 {
+                            writeln!(f)?;
+                        }
+                        write!(f, "{}", record)?;
+                    }
+                    Ok(())
+                }
+                LogRecord::Stderr { program, output } => {
+                    write!(f, "{}: {}", program, output.trim())
+                }
+                LogRecord::Retry { program, timeout } => {
+                    write!(f, "{}: timed out, retrying with {:?}", program, timeout)
+                }
+                LogRecord::Warning(message) => write!(f, "warning: {}", message),
+                LogRecord::Timing { stage, duration } => write!(f, "{}: {:.1?}", stage, duration),
+            }
+        }
+    }
+
+    pub fn parse_pdflatex_logs(input: &str) -> Result<Vec<PdflatexLogRecord>, Box<dyn std::error::Error>> {
+
+        let re = regex::Regex::new(r"!(.*?)\n(l\.\d+) (.*?)(\n!|\n\(|\n|$)")?;
+
+        let mut res = Vec::new();
+
+        for cap in re.captures_iter(input) {
+            let info = cap.get(1).unwrap().as_str();
+            let line = cap.get(2).unwrap().as_str();
+            let content = cap.get(3).unwrap().as_str();
+
+            res.push(PdflatexLogRecord {
+                line: line.to_string(),
+                info: info.to_string(),
+                content: content.to_string(),
+            })
+        }
+
+        Ok(res)
+    }
+
+    /// `LaTeX`, package and class warnings as well as over- and underfull boxes in `pdflatex`
+    /// output, one line each.
+    pub fn parse_pdflatex_warnings(input: &str) -> Vec<String> {
+        input
+            .lines()
+            .filter(|line| {
+                line.contains(" Warning: ")
+                    || line.starts_with(r#"Overfull \"#)
+                    || line.starts_with(r#"Underfull \"#)
+            })
+            .map(|line| line.trim().to_string())
+            .collect()
+    }
+
+    /// How often [`run`] checks whether a program with a timeout has exited.
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Run `command` to completion like [`Command::output`], but kill it once `timeout` has
+    /// passed. Returns `None` if it was killed.
+    pub(crate) fn run(
+        command: &mut Command,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<Option<Output>> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return command.output().map(Some),
+        };
+
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Read both pipes while waiting, a program filling one would block otherwise.
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+
+            if Instant::now() >= deadline {
+                // Programs the killed one started may keep the pipes open, so the readers are
+                // left to finish on their own.
+                let _ = child.kill();
+                child.wait()?;
+                return Ok(None);
+            }
+
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        Ok(Some(Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        }))
+    }
+
+    /// Read `pipe` to the end on another thread.
+    fn drain<R: std::io::Read + Send + 'static>(
+        pipe: Option<R>,
+    ) -> std::thread::JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let mut data = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut data);
+            }
+            data
+        })
+    }
+
+    /// Look `program` up on `PATH` the way a shell would, returning its full path.
+    pub fn find_program(program: &str) -> Option<PathBuf> {
+        let exe = format!("{}{}", program, std::env::consts::EXE_SUFFIX);
+
+        std::env::var_os("PATH").and_then(|paths| {
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(&exe))
+                .find(|path| path.is_file())
+        })
+    }
+
+    /// Create a subdirectory of `root` that did not exist before, so removing it afterwards never
+    /// takes anything of the user's with it.
+    fn fresh_subdir(root: &Path) -> std::io::Result<PathBuf> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        std::fs::create_dir_all(root)?;
+        loop {
+            let path = root.join(format!(
+                "teximex-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            match std::fs::create_dir(&path) {
+                Ok(()) => return Ok(path),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Those of `programs` [`find_program`] can't find.
+    pub(crate) fn missing_programs(programs: &[&str]) -> Vec<String> {
+        programs
+            .iter()
+            .filter(|program| find_program(program).is_none())
+            .map(|program| program.to_string())
+            .collect()
+    }
+
+    /// Where distributions commonly install the Ghostscript shared library.
+    const GHOSTSCRIPT_LIBS: &[&str] = &[
+        "/usr/lib/libgs.so",
+        "/usr/lib/x86_64-linux-gnu/libgs.so.10",
+        "/usr/lib/x86_64-linux-gnu/libgs.so.9",
+        "/usr/lib/aarch64-linux-gnu/libgs.so.10",
+        "/usr/lib/aarch64-linux-gnu/libgs.so.9",
+        "/usr/lib64/libgs.so",
+        "/usr/local/lib/libgs.dylib",
+        "/opt/homebrew/lib/libgs.dylib",
+        "/run/current-system/sw/lib/libgs.so",
+    ];
+
+    /// The Ghostscript library `dvisvgm` should load, if it needs to be set at all.
+    ///
+    /// An explicit [`RenderOptions::ghostscript_lib`] wins. An existing `LIBGS` in the environment
+    /// is left alone, otherwise the first of [`GHOSTSCRIPT_LIBS`] that exists is used.
+    fn ghostscript_lib(options: &RenderOptions) -> Option<PathBuf> {
+        if let Some(path) = options.ghostscript_lib() {
+            return Some(path.clone());
+        }
+
+        if std::env::var_os("LIBGS").is_some() {
+            return None;
+        }
+
+        GHOSTSCRIPT_LIBS
+            .iter()
+            .map(Path::new)
+            .find(|path| path.exists())
+            .map(Path::to_path_buf)
+    }
+
+    /// `TEXINPUTS` searching `dir` first, then `existing` or, when that is unset, TeX's default
+    /// path.
+    pub fn texinputs(dir: &Path, existing: Option<&std::ffi::OsStr>) -> std::ffi::OsString {
+        let separator = if cfg!(windows) { ";" } else { ":" };
+
+        let mut texinputs = dir.as_os_str().to_owned();
+        texinputs.push(separator);
+        if let Some(existing) = existing {
+            texinputs.push(existing);
+        }
+        texinputs
+    }
+
+    // #[derive(Debug, Clone)]
+    // pub struct NativeLogRecord {
+    //     pub kind: tectonic::status::MessageKind,
+    //     pub args: String,
+    // }
+
+    pub struct RenderInstanceNative {
+        pub instance: RenderInstance<String, Loaded>,
+        pub path_root: PathBuf,
+        pub logs: Vec<LogRecord>,
+        pub cache: Option<RenderCache>,
+        /// Wrap the content in a `preview` environment so `dvisvgm` reports its depth.
+        preview: bool,
+        depth_pt: Option<f32>,
+        cleanup_on_drop: bool,
+        /// Skip the cleanup on drop while the last render failed.
+        keep_on_error: bool,
+        /// A render started compiling and has not finished successfully since.
+        failed: bool,
+        last_dimensions: Option<(u32, u32)>,
+        cancel: Option<CancelToken>,
+        usvg_options: Option<usvg::Options>,
+        on_progress: Option<Box<dyn Fn(RenderStage) + Send>>,
+    }
+
+    /// Files a render leaves in the root directory after the [`RenderOptions::job_name`],
+    /// removed by [`RenderInstanceNative::clean`] together with the output images.
+    const ARTIFACTS: &[&str] = &[
+        ".tex", ".aux", ".log", ".dvi", ".pdf", "2.dvi", "2.svg", "2.png", "2.jpg", "2.webp",
+    ];
+
+    /// Depth of the box `dvisvgm --bbox=preview` reports, in TeX points.
+    pub fn parse_dvisvgm_depth(output: &str) -> Option<f32> {
+        let start = output.find("depth=")? + "depth=".len();
+        let rest = &output[start..];
+        rest[..rest.find("pt")?].trim().parse().ok()
+    }
+
+    impl RenderInstanceNative {
+        pub fn new<P: Into<PathBuf>>(root: P, instance: RenderInstance<String, Loaded>) -> Self {
+            Self {
+                instance,
+                path_root: root.into(),
+                logs: Vec::new(),
+                cache: None,
+                preview: false,
+                depth_pt: None,
+                cleanup_on_drop: false,
+                keep_on_error: false,
+                failed: false,
+                last_dimensions: None,
+                cancel: None,
+                usvg_options: None,
+                on_progress: None,
+            }
+        }
+
+        /// Call [`RenderInstanceNative::clean`] when the instance is dropped.
+        pub fn with_cleanup_on_drop(mut self) -> Self {
+            self.cleanup_on_drop = true;
+            self
+        }
+
+        /// Like [`RenderInstanceNative::with_cleanup_on_drop`], but leave the files in place when
+        /// the last render failed so they can be inspected, see
+        /// [`RenderInstanceNative::tex_path`].
+        pub fn with_cleanup_on_success(mut self) -> Self {
+            self.cleanup_on_drop = true;
+            self.keep_on_error = true;
+            self
+        }
+
+        /// Where the `.tex` of a render is written, with the `.log` of the compilation next to
+        /// it. Useful to point at the sources of a failed render.
+        pub fn tex_path(&self) -> PathBuf {
+            self.artifact(".tex")
+        }
+
+        /// Remove the files rendering wrote to the root directory, leaving the directory itself,
+        /// the cache and anything else in it alone.
+        ///
+        /// Paths to the outputs of earlier renders (such as `out.png`) are invalid afterwards,
+        /// rendering again writes them anew.
+        pub fn clean(&self) -> Result<(), std::io::Error> {
+            let artifacts = ARTIFACTS.iter().map(|suffix| self.artifact(suffix));
+            let outputs = [
+                RenderFormat::Png,
+                RenderFormat::Jpeg { quality: 0 },
+                RenderFormat::Webp,
+            ]
+            .map(|format| self.output_path(format));
+
+            for artifact in artifacts.chain(outputs) {
+                match std::fs::remove_file(artifact) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+
+            Ok(())
+        }
+
+        /// Like [`RenderBackend::render`], but also reports where the baseline of the formula is.
+        ///
+        /// The content is compiled inside a `preview` environment so `dvisvgm` can report the
+        /// depth below the baseline. The baseline accounts for the scale, resolution and margin,
+        /// but not for [`RenderOptions::set_fit`] or auto-cropping. Bypasses the cache.
+        pub fn render_with_metadata(&mut self) -> Result<RenderResult, RenderError> {
+            self.preview = true;
+            let tex = String::from_utf8_lossy(&self.create_tex()).into_owned();
+            let svg = self.compile_to_svg();
+            self.preview = false;
+
+            let data = self.create_image(&svg?)?;
+            let options = &self.instance.options;
+
+            // dvisvgm reports TeX points, usvg lays the SVG out at 96 DPI.
+            let baseline_px = self.depth_pt.map(|depth| {
+                depth * 96.0 / 72.27 * options.scale() * options.zoom() + options.margin()
+            });
+
+            let warnings = self
+                .warnings()
+                .filter_map(|record| match record {
+                    LogRecord::Warning(message) => Some(message.clone()),
+                    _ => None,
+                })
+                .collect();
+
+            self.finish();
+            Ok(RenderResult {
+                data,
+                baseline_px,
+                warnings,
+                root: self.path_root.clone(),
+                tex,
+            })
+        }
+
+        /// `(width, height)` in pixels of the image the last successful render produced.
+        ///
+        /// Saves decoding the image just to size it. Only known for PNG when the image came
+        /// from the cache.
+        pub fn last_dimensions(&self) -> Option<(u32, u32)> {
+            self.last_dimensions
+        }
+
+        /// Render into an [`image::RgbaImage`], converting the rasterized pixmap directly instead
+        /// of encoding and decoding it. The [`RenderOptions::format`] is ignored.
+        ///
+        /// With a cache set, the cached image is decoded instead since the cache stores encoded
+        /// images, see [`RenderInstanceNative::with_cache`].
+        pub fn render_image(&mut self) -> Result<image::RgbaImage, RenderError> {
+            if self.cache().is_some() {
+                let data = self.render()?;
+                return image::load_from_memory(&data)
+                    .map(|image| image.into_rgba8())
+                    .map_err(|e| RenderError::Encode(e.to_string()));
+            }
+
+            let svg = self.compile_to_svg()?;
+            let pixmap = self.create_pixmap(&svg)?;
+
+            self.finish();
+            Ok(post_process::to_rgba_image(&pixmap))
+        }
+
+        /// Compile straight to PDF, skipping rasterization, for inclusion in other documents.
+        ///
+        /// The page is cropped tightly to the content with the `preview` package. Only the
+        /// document options apply, none of the [`RenderOptions`] beyond the timeout and retries
+        /// do. Bypasses the cache.
+        pub fn render_pdf(&mut self) -> Result<Vec<u8>, RenderError> {
+            self.begin();
+            self.check_sandbox()?;
+            self.validate()?;
+            self.check_dependencies()
+                .map_err(|missing| RenderError::BackendUnavailable(missing.join(", ")))?;
+
+            self.preview = true;
+            let tex = self.create_tex();
+            self.preview = false;
+
+            let pdf = self.compile(&tex, "pdf")?;
+            self.finish();
+            Ok(pdf)
+        }
+
+        /// [`LogRecord::Warning`]s in [`RenderInstanceNative::logs`].
+        pub fn warnings(&self) -> impl Iterator<Item = &LogRecord> {
+            self.logs.iter().filter(|record| record.is_warning())
+        }
+
+        /// Records in [`RenderInstanceNative::logs`] for which [`LogRecord::is_error`] holds.
+        pub fn errors(&self) -> impl Iterator<Item = &LogRecord> {
+            self.logs.iter().filter(|record| record.is_error())
+        }
+
+        /// Abandon rendering with [`RenderError::Cancelled`] once `token` is cancelled. Checked
+        /// when a render starts, before each external program runs and before rasterizing and
+        /// encoding.
+        pub fn with_cancel_token(mut self, token: CancelToken) -> Self {
+            self.cancel = Some(token);
+            self
+        }
+
+        /// Parse SVGs with `options` instead of the defaults with the system fonts loaded, e.g.
+        /// to supply bundled fonts on a headless server or change the default DPI.
+        ///
+        /// Relative references in the SVG are resolved against `options.resources_dir`. The
+        /// options are not part of the cache key, so renders with them bypass
+        /// [`RenderInstanceNative::with_cache`].
+        pub fn with_usvg_options(mut self, options: usvg::Options) -> Self {
+            self.usvg_options = Some(options);
+            self
+        }
+
+        /// Call `callback` as each [`RenderStage`] begins, e.g. to show what a long render is
+        /// doing. Called on the thread rendering.
+        pub fn on_progress(mut self, callback: impl Fn(RenderStage) + Send + 'static) -> Self {
+            self.on_progress = Some(Box::new(callback));
+            self
+        }
+
+        /// Forget what the previous render left behind, so the logs, dimensions and failure
+        /// state only ever describe the current one.
+        fn begin(&mut self) {
+            self.logs.clear();
+            self.failed = false;
+            self.last_dimensions = None;
+        }
+
+        /// Report [`RenderStage::Done`] and remember the render succeeded.
+        fn finish(&mut self) {
+            self.failed = false;
+            self.progress(RenderStage::Done);
+        }
+
+        fn progress(&self, stage: RenderStage) {
+            if let Some(callback) = &self.on_progress {
+                callback(stage);
+            }
+        }
+
+        fn check_sandbox(&self) -> Result<(), RenderError> {
+            let violations = self.instance.document().sandbox_violations();
+            if violations.is_empty() {
+                Ok(())
+            } else {
+                Err(RenderError::Unsafe(violations.join(", ")))
+            }
+        }
+
+        /// [`RenderOptions::validate`] and [`crate::document::DocumentOptions::validate`].
+        fn validate(&self) -> Result<(), RenderError> {
+            self.instance.options.validate()?;
+            self.instance
+                .document()
+                .options()
+                .validate()
+                .map_err(|err| RenderError::InvalidOptions(err.to_string()))
+        }
+
+        fn check_cancelled(&self) -> Result<(), RenderError> {
+            match &self.cancel {
+                Some(token) if token.is_cancelled() => Err(RenderError::Cancelled),
+                _ => Ok(()),
+            }
+        }
+
+        pub fn with_cache(mut self, cache: RenderCache) -> Self {
+            self.cache = Some(cache);
+            self
+        }
+
+        /// The cache to use, `None` with custom usvg options since they change the image without
+        /// changing the key.
+        fn cache(&self) -> Option<&RenderCache> {
+            match self.usvg_options {
+                Some(_) => None,
+                None => self.cache.as_ref(),
+            }
+        }
+
+        /// Render many documents sharing `options` in parallel.
+        ///
+        /// Each document gets its own fresh subdirectory of `root` so the compilations don't
+        /// clobber each other, removed once it is rendered. Results are returned in input order
+        /// and one failing document does not abort the others.
+        pub fn render_batch<P: Into<PathBuf>>(
+            root: P,
+            documents: &[Document<String>],
+            options: &RenderOptions,
+        ) -> Vec<Result<Vec<u8>, RenderError>> {
+            let root = root.into();
+
+            documents
+                .par_iter()
+                .map(|document| {
+                    Self::render_in_subdir(&root, document, options, |native| native.render())
+                })
+                .collect()
+        }
+
+        /// Render `document` with `render` in a fresh subdirectory of `root`, removed afterwards,
+        /// successful or not. Failing to remove it does not fail the render.
+        fn render_in_subdir<R>(
+            root: &Path,
+            document: &Document<String>,
+            options: &RenderOptions,
+            render: impl FnOnce(&mut RenderInstanceNative) -> Result<R, RenderError>,
+        ) -> Result<R, RenderError> {
+            let path = fresh_subdir(root)?;
+
+            let instance = RenderInstance::new_with_options(options.clone()).load(document.clone());
+            let mut native = RenderInstanceNative::new(&path, instance);
+            let result = render(&mut native);
+
+            drop(native);
+            let _ = std::fs::remove_dir_all(&path);
+            result
+        }
+
+        /// Check that every external program the backend runs is on `PATH`.
+        ///
+        /// Returns the names of the missing ones. [`RenderBackend::render`] runs this before
+        /// compiling and fails with [`RenderError::BackendUnavailable`] listing them.
+        pub fn check_dependencies(&self) -> Result<(), Vec<String>> {
+            let programs = match self.instance.options.toolchain() {
+                Toolchain::LatexDvi => ["pdflatex", "dvisvgm"],
+                Toolchain::Pdflatex => ["pdflatex", "pdf2svg"],
+            };
+
+            let missing = missing_programs(&programs);
+
+            if missing.is_empty() {
+                Ok(())
+            } else {
+                Err(missing)
+            }
+        }
+
+        /// Render many documents sharing `options` into a single sprite sheet.
+        ///
+        /// Documents are compiled in parallel like [`RenderInstanceNative::render_batch`] and
+        /// tiled with [`post_process::tile`]. Returns the encoded sheet and where each document
+        /// ended up on it, in input order. Fails if any document fails.
+        pub fn render_sheet<P: Into<PathBuf>>(
+            root: P,
+            documents: &[Document<String>],
+            options: &RenderOptions,
+            layout: SheetLayout,
+        ) -> Result<(Vec<u8>, Vec<SheetRect>), RenderError> {
+            let root = root.into();
+
+            let pixmaps = documents
+                .par_iter()
+                .map(|document| {
+                    Self::render_in_subdir(&root, document, options, |native| {
+                        let svg = native.compile_to_svg()?;
+                        native.create_pixmap(&svg)
+                    })
+                })
+                .collect::<Result<Vec<_>, RenderError>>()?;
+
+            let (sheet, rects) = post_process::tile(&pixmaps, layout, options.background())?;
+            let sheet = match options.format() {
+                // The gaps between the tiles are not flattened yet.
+                RenderFormat::Jpeg { .. } => post_process::flatten(&sheet, options.matte()),
+                _ => sheet,
+            };
+            let data = post_process::encode(&sheet, options.format())?;
+
+            Ok((data, rects))
+        }
+
+        /// Run `program` set up by `configure`, retrying with a doubled timeout up to
+        /// [`RenderOptions::max_retries`] times while it times out. Fails with
+        /// [`RenderError::Timeout`] when the last retry times out as well.
+        pub(crate) fn output(
+            &mut self,
+            program: &str,
+            configure: impl Fn(&mut Command),
+        ) -> Result<Output, RenderError> {
+            let mut timeout = self.instance.options.timeout();
+            let mut retries = self.instance.options.max_retries();
+
+            loop {
+                self.check_cancelled()?;
+
+                let mut command = Command::new(program);
+                configure(&mut command);
+
+                if let Some(output) =
+                    run(&mut command, timeout).map_err(|e| RenderError::spawn(program, e))?
+                {
+                    return Ok(output);
+                }
+
+                // Only programs with a timeout are killed.
+                let limit = timeout.unwrap_or_default();
+                if retries == 0 {
+                    return Err(RenderError::Timeout {
+                        program: program.to_string(),
+                        timeout: limit,
+                    });
+                }
+
+                retries -= 1;
+                timeout = Some(limit * 2);
+                self.logs.push(LogRecord::Retry {
+                    program: program.to_string(),
+                    timeout: limit * 2,
+                });
+            }
+        }
+
+        /// `{job}{suffix}` in the root directory, see [`RenderOptions::job_name`].
+        fn artifact(&self, suffix: &str) -> PathBuf {
+            self.path_root
+                .join(format!("{}{}", self.instance.options.job_name(), suffix))
+        }
+
+        /// Where [`RenderBackend::render`] writes the final image, `out` unless a
+        /// [`RenderOptions::job_name`] is set.
+        fn output_path(&self, format: RenderFormat) -> PathBuf {
+            match self.instance.options.job_name() {
+                RenderOptions::DEFAULT_JOB_NAME => {
+                    self.path_root.join(format!("out.{}", format.extension()))
+                }
+                _ => self.artifact(&format!("-out.{}", format.extension())),
+            }
+        }
 
-    pub fn parse_pdflatex_logs(input: &str) -> Result<Vec<PdflatexLogRecord>, Box<dyn std::error::Error>> {
+        /// Arguments to `pdflatex` compiling the job's `.tex` to `output_format`.
+        fn pdflatex_args(&self, output_format: &str) -> Vec<String> {
+            let job_name = self.instance.options.job_name();
+            let mut args = vec![
+                format!("-jobname={}", job_name),
+                format!("-output-format={}", output_format),
+                "-interaction=nonstopmode".to_string(),
+            ];
+
+            let options = self.instance.document().options();
+            if options.sandbox {
+                args.push("-no-shell-escape".to_string());
+            } else if options.externalize {
+                args.push("-shell-escape".to_string());
+            }
 
-        let re = regex::Regex::new(r"!(.*?)\n(l\.\d+) (.*?)(\n!|\n\(|\n|$)")?;
+            args.push(format!("{}.tex", job_name));
+            args
+        }
 
-        let mut res = Vec::new();
+        /// Arguments to `dvisvgm` converting the job's `2.dvi`.
+        fn dvisvgm_args(&self) -> Vec<String> {
+            let mut args = vec![
+                format!("{}2.dvi", self.instance.options.job_name()),
+                "--no-fonts".to_string(),
+                format!("--scale={}", self.instance.options.scale()),
+            ];
 
-        for cap in re.captures_iter(input) {
-            let info = cap.get(1).unwrap().as_str();
-            let line = cap.get(2).unwrap().as_str();
-            let content = cap.get(3).unwrap().as_str();
+            if self.preview {
+                args.push("--bbox=preview".to_string());
+            }
 
-            res.push(PdflatexLogRecord {
-                line: line.to_string(),
-                info: info.to_string(),
-                content: content.to_string(),
-            })
+            args
         }
 
-        return Ok(res)
-    }
+        /// Arguments to `pdf2svg` converting the job's `.pdf`.
+        fn pdf2svg_args(&self) -> Vec<String> {
+            let job_name = self.instance.options.job_name();
+            vec![format!("{}.pdf", job_name), format!("{}2.svg", job_name)]
+        }
 
-    // #[derive(Debug, Clone)]
-    // pub struct NativeLogRecord {
-    //     pub kind: tectonic::status::MessageKind,
-    //     pub args: String,
-    // }
+        /// The command lines [`RenderBackend::render`] runs, in order, without running anything.
+        ///
+        /// Each is the program followed by its arguments. All run in [`RenderInstanceNative::path_root`], with
+        /// `LIBGS` set for `dvisvgm` when a Ghostscript library is found and `TEXINPUTS` for
+        /// `pdflatex` when the document has a
+        /// [`crate::document::DocumentOptions::resource_dir`]. Useful to reproduce a failing
+        /// render by hand.
+        pub fn render_commands(&self) -> Vec<Vec<String>> {
+            let commands = match self.instance.options.toolchain() {
+                Toolchain::LatexDvi => [
+                    ("pdflatex", self.pdflatex_args("dvi")),
+                    ("dvisvgm", self.dvisvgm_args()),
+                ],
+                Toolchain::Pdflatex => [
+                    ("pdflatex", self.pdflatex_args("pdf")),
+                    ("pdf2svg", self.pdf2svg_args()),
+                ],
+            };
 
-    pub struct RenderInstanceNative {
-        pub instance: RenderInstance<String, Loaded>,
-        pub path_root: PathBuf,
-        pub logs: Vec<LogRecord>,
-    }
+            commands
+                .into_iter()
+                .map(|(program, args)| std::iter::once(program.to_string()).chain(args).collect())
+                .collect()
+        }
 
-    impl RenderInstanceNative {
-        pub fn new<P: Into<PathBuf>>(root: P, instance: RenderInstance<String, Loaded>) -> Self {
-            Self {
-                instance,
-                path_root: root.into(),
-                logs: Vec::new(),
+        pub(crate) fn create_tex(&self) -> Vec<u8> {
+            let document = self.instance.document();
+            // pdf2svg keeps the whole page, `preview` crops it to the content.
+            if !self.preview && self.instance.options.toolchain() != Toolchain::Pdflatex {
+                return document.to_tex().as_bytes().to_vec();
             }
-        }
 
-        fn create_tex(&self) -> Vec<u8> {
-            self.instance.document().to_tex().as_bytes().to_vec()
+            let mut options = document.options().clone();
+            options
+                .preamble
+                .push_str("\n\\usepackage[active,tightpage]{preview}\n");
+            // The `varwidth` box goes inside the `preview` environment, not around it.
+            if options.max_width_cm.take().is_some() {
+                options.preamble.push_str("\\usepackage{varwidth}\n");
+            }
+            let content = format!(r#"\begin{{preview}}{}\end{{preview}}"#, document.body());
+
+            Document::new_with_options(content, options)
+                .to_tex()
+                .as_bytes()
+                .to_vec()
         }
 
-        fn _create_dvi(&mut self, tex: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-            
-            println!("{:?}", self.path_root);
+        fn _create_dvi(&mut self, tex: &[u8]) -> Result<Vec<u8>, RenderError> {
+            self.compile(tex, "dvi")
+        }
 
-            let mut tp_path = self.path_root.clone();
-            tp_path.push("texput");
-            tp_path.set_extension("tex");
+        /// Run `pdflatex` on `tex`, returning the `dvi` or `pdf` it produced as `output_format`.
+        fn compile(&mut self, tex: &[u8], output_format: &str) -> Result<Vec<u8>, RenderError> {
+            self.failed = true;
+            self.progress(RenderStage::WritingTex);
 
-            let mut texput = File::create(&tp_path)?;
+            let mut texput = File::create(self.artifact(".tex"))?;
             texput.write_all(tex)?;
 
-            let pdflatex = Command::new("pdflatex")
-                .arg("-jobname=texput")
-                .arg("-output-format=dvi")
-                .arg("-interaction=nonstopmode")
-                .arg("texput.tex")
-                .current_dir(&self.path_root)
-                .output()?;
+            let externalize = self.instance.document().options().externalize;
+            if externalize {
+                let dir = self.instance.document().options().externalize_dir();
+                std::fs::create_dir_all(self.path_root.join(dir))?;
+            }
 
-            let output = String::from_utf8_lossy(&pdflatex.stdout);
+            let root = self.path_root.clone();
+            let args = self.pdflatex_args(output_format);
+            let deterministic = self.instance.options.deterministic();
+            let texinputs = match &self.instance.document().options().resource_dir {
+                Some(dir) => Some(texinputs(
+                    &std::env::current_dir()?.join(dir),
+                    std::env::var_os("TEXINPUTS").as_deref(),
+                )),
+                None => None,
+            };
 
-            let logs = parse_pdflatex_logs(&output);
+            self.progress(RenderStage::Compiling);
+            let start = Instant::now();
+            let pdflatex = self.output("pdflatex", |command| {
+                command.args(&args).current_dir(&root);
+
+                if let Some(texinputs) = &texinputs {
+                    command.env("TEXINPUTS", texinputs);
+                }
+
+                // Also pins the dates pdfTeX writes into the DVI/PDF and `\today`.
+                if deterministic {
+                    command
+                        .env("SOURCE_DATE_EPOCH", "0")
+                        .env("FORCE_SOURCE_DATE", "1");
+                }
+            })?;
+            self.logs.push(LogRecord::Timing {
+                stage: "compile",
+                duration: start.elapsed(),
+            });
 
-            println!("{:?}", logs);
+            let output = String::from_utf8_lossy(&pdflatex.stdout);
 
+            let logs = parse_pdflatex_logs(&output);
             self.logs.push(LogRecord::Pdflatex(logs.unwrap()));
+            self.logs.extend(
+                parse_pdflatex_warnings(&output)
+                    .into_iter()
+                    .map(LogRecord::Warning),
+            );
+
+            // A failed run may leave stale output from an earlier render behind.
+            if !pdflatex.status.success() {
+                let stderr = String::from_utf8_lossy(&pdflatex.stderr);
+                if !stderr.trim().is_empty() {
+                    self.logs.push(LogRecord::Stderr {
+                        program: "pdflatex".to_string(),
+                        output: stderr.into_owned(),
+                    });
+                }
+                return Err(RenderError::Compile(self.logs.clone()));
+            }
 
-            let mut tp_path_dvi = self.path_root.clone();
-            tp_path_dvi.push("texput");
-            tp_path_dvi.set_extension("dvi");
-
-            let data = std::fs::read(&tp_path_dvi)?;
+            let data = std::fs::read(self.artifact(&format!(".{}", output_format)))
+                .map_err(|_| RenderError::Compile(self.logs.clone()))?;
 
             Ok(data)
         }
@@ -215,81 +2393,236 @@ pub mod native {
                 session.into_file_data()
             };
 
-            println!("{:?}", self.logs);
-
             let data = files.remove("texput.xdv").unwrap().data;
             Ok(data)
         }
         */
 
-        fn create_png(&self, dvi: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        fn create_svg(&mut self, dvi: Vec<u8>) -> Result<Vec<u8>, RenderError> {
+            let mut file = File::create(self.artifact("2.dvi"))?;
+            file.write_all(&dvi[..])?;
+
+            let root = self.path_root.clone();
+            let args = self.dvisvgm_args();
+            let libgs = ghostscript_lib(&self.instance.options);
+
+            self.progress(RenderStage::ConvertingSvg);
+            let start = Instant::now();
+            let output = self.output("dvisvgm", |dvisvgm| {
+                dvisvgm.args(&args).current_dir(&root);
+
+                if let Some(libgs) = &libgs {
+                    dvisvgm.env("LIBGS", libgs);
+                }
+            })?;
+
+            self.logs.push(LogRecord::Timing {
+                stage: "svg",
+                duration: start.elapsed(),
+            });
+
+            if !output.status.success() {
+                self.logs.push(LogRecord::Stderr {
+                    program: "dvisvgm".to_string(),
+                    output: String::from_utf8_lossy(&output.stderr).into_owned(),
+                });
+                return Err(RenderError::Compile(self.logs.clone()));
+            }
 
-            dbg!("{:?}", &self.path_root);
+            self.depth_pt = parse_dvisvgm_depth(&format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ));
 
-            let mut path = self.path_root.clone();
-            path.push("texput2");
-            path.set_extension("dvi");
+            let svg_data = std::fs::read(self.artifact("2.svg"))?;
 
-            let mut file = File::create(path)?;
-            file.write_all(&dvi[..])?;
+            Ok(svg_data)
+        }
 
-            Command::new("dvisvgm")
-                .arg("texput2.dvi")
-                .arg("--no-fonts")
-                .arg(format!("--scale={}", self.instance.options.scale()))
-                .current_dir(&self.path_root)
-                .output()?;
-
-            let mut svg_opt = usvg::Options::default();
-            svg_opt.resources_dir = std::fs::canonicalize(&self.path_root)
-                .ok()
-                .and_then(|p| p.parent().map(|p| p.to_path_buf()));
-            svg_opt.fontdb.load_system_fonts();
-
-            let mut svg_path = self.path_root.clone();
-            svg_path.push("texput2");
-            svg_path.set_extension("svg");
-
-            let svg_data = std::fs::read(&svg_path)?;
-
-            let rtree = usvg::Tree::from_data(&svg_data, &svg_opt.to_ref())?;
-            let pixmap_size = rtree.size.to_screen_size();
-            let mut pixmap =
-                tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height()).unwrap();
-
-            resvg::render(
-                &rtree,
-                usvg::FitTo::Original,
-                tiny_skia::Transform::default(),
-                pixmap.as_mut(),
-            )
-            .unwrap();
+        /// Convert the `.pdf` written by [`RenderInstanceNative::compile`] with `pdf2svg`.
+        fn create_svg_from_pdf(&mut self) -> Result<Vec<u8>, RenderError> {
+            let root = self.path_root.clone();
+            let args = self.pdf2svg_args();
+            self.progress(RenderStage::ConvertingSvg);
+            let start = Instant::now();
+            let output = self.output("pdf2svg", |pdf2svg| {
+                pdf2svg.args(&args).current_dir(&root);
+            })?;
+            self.logs.push(LogRecord::Timing {
+                stage: "svg",
+                duration: start.elapsed(),
+            });
+
+            if !output.status.success() {
+                self.logs.push(LogRecord::Stderr {
+                    program: "pdf2svg".to_string(),
+                    output: String::from_utf8_lossy(&output.stderr).into_owned(),
+                });
+                return Err(RenderError::Compile(self.logs.clone()));
+            }
+
+            // Only `dvisvgm` reports the depth.
+            self.depth_pt = None;
 
-            let mut png_path = self.path_root.clone();
-            png_path.push("texput2");
-            png_path.set_extension("png");
+            Ok(std::fs::read(self.artifact("2.svg"))?)
+        }
+
+        /// Compile the document to SVG, the first half of every render. Unlike
+        /// [`RenderBackend::render_svg`] this does not report [`RenderStage::Done`], callers carry
+        /// on rasterizing.
+        fn compile_to_svg(&mut self) -> Result<Vec<u8>, RenderError> {
+            self.begin();
+            self.check_cancelled()?;
+            self.check_sandbox()?;
+            self.validate()?;
+            self.check_dependencies()
+                .map_err(|missing| RenderError::BackendUnavailable(missing.join(", ")))?;
+
+            let tex = self.create_tex();
+            let svg = match self.instance.options.toolchain() {
+                Toolchain::LatexDvi => {
+                    let dvi = self._create_dvi(&tex)?;
+                    self.create_svg(dvi)?
+                }
+                Toolchain::Pdflatex => {
+                    self.compile(&tex, "pdf")?;
+                    self.create_svg_from_pdf()?
+                }
+            };
+
+            if self.instance.options.deterministic() {
+                return Ok(post_process::strip_svg_metadata(&svg));
+            }
+            Ok(svg)
+        }
+
+        fn create_pixmap(&mut self, svg_data: &[u8]) -> Result<tiny_skia::Pixmap, RenderError> {
+            self.check_cancelled()?;
+            self.progress(RenderStage::Rasterizing);
+
+            let start = Instant::now();
+            let default_opt;
+            let svg_opt = match &self.usvg_options {
+                Some(svg_opt) => svg_opt,
+                None => {
+                    let mut svg_opt = usvg::Options {
+                        resources_dir: std::fs::canonicalize(&self.path_root)
+                            .ok()
+                            .and_then(|p| p.parent().map(|p| p.to_path_buf())),
+                        ..Default::default()
+                    };
+                    svg_opt.fontdb.load_system_fonts();
+
+                    default_opt = svg_opt;
+                    &default_opt
+                }
+            };
+
+            let rtree = usvg::Tree::from_data(svg_data, &svg_opt.to_ref())?;
+
+            // `pdf2svg` has no `--scale`, zooming while rasterizing has the same effect.
+            let mut options = self.instance.options.clone();
+            if let (Toolchain::Pdflatex, Resolution::Scale(scale)) =
+                (options.toolchain(), options.resolution())
+            {
+                options.set_resolution(Resolution::Dpi(scale * 96.0));
+            }
+
+            let pixmap = post_process::rasterize(&rtree, &options)?;
+            self.logs.push(LogRecord::Timing {
+                stage: "raster",
+                duration: start.elapsed(),
+            });
+
+            self.last_dimensions = Some((pixmap.width(), pixmap.height()));
+            Ok(pixmap)
+        }
 
-            pixmap.save_png(&png_path)?;
+        fn create_image(&mut self, svg_data: &[u8]) -> Result<Vec<u8>, RenderError> {
+            let pixmap = self.create_pixmap(svg_data)?;
+            self.check_cancelled()?;
+
+            let format = self.instance.options.format();
+            let start = Instant::now();
+            let mut data = post_process::encode(&pixmap, format)?;
+            self.logs.push(LogRecord::Timing {
+                stage: "encode",
+                duration: start.elapsed(),
+            });
+
+            if self.instance.options.embed_source() && format == RenderFormat::Png {
+                data = post_process::embed_source(&data, &self.instance.document().to_tex());
+            }
+
+            if self.instance.options.embed_srgb() && format == RenderFormat::Png {
+                data = post_process::embed_srgb(&data);
+            }
+
+            std::fs::write(self.artifact(&format!("2.{}", format.extension())), &data)?;
 
-            let data = std::fs::read(png_path)?;
             Ok(data)
         }
     }
 
+    impl Drop for RenderInstanceNative {
+        fn drop(&mut self) {
+            if self.cleanup_on_drop && !(self.keep_on_error && self.failed) {
+                let _ = self.clean();
+            }
+        }
+    }
+
     impl RenderBackend for RenderInstanceNative {
-        fn render(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-            let tex = self.create_tex();
-            let dvi = self._create_dvi(&tex)?;
-            let png = self.create_png(dvi)?;
+        fn render(&mut self) -> Result<Vec<u8>, RenderError> {
+            self.begin();
+
+            // Neither is part of the key, a cached image must not get around them.
+            self.check_sandbox()?;
+            self.validate()?;
+
+            let key = RenderCache::key(&self.instance.document().to_tex(), &self.instance.options);
+            if let Some(data) = self.cache().and_then(|cache| cache.get(key)) {
+                self.last_dimensions = post_process::png_dimensions(&data);
+                self.finish();
+                return Ok(data);
+            }
+
+            let svg = self.compile_to_svg()?;
+            let image = self.create_image(&svg)?;
+
+            if let Some(cache) = self.cache() {
+                cache.insert(key, &image)?;
+            }
+
+            let mut file = File::create(self.output_path(self.instance.options.format()))?;
+            file.write_all(&image)?;
 
-            let mut path = self.path_root.clone();
-            path.push("out");
-            path.set_extension("png");
+            self.finish();
+            Ok(image.to_vec())
+        }
+
+        /// Encodes straight into `writer` unless a cache is set or chunks are embedded, which
+        /// need the complete image and fall back to [`RenderBackend::render`].
+        fn render_to<W: std::io::Write>(&mut self, writer: &mut W) -> Result<(), RenderError> {
+            let options = &self.instance.options;
+            if self.cache().is_some() || options.embed_source() || options.embed_srgb() {
+                writer.write_all(&self.render()?)?;
+                return Ok(());
+            }
+
+            let svg = self.compile_to_svg()?;
+            let pixmap = self.create_pixmap(&svg)?;
+            post_process::encode_to(&pixmap, self.instance.options.format(), writer)?;
 
-            let mut file = File::create(path)?;
-            file.write(&png)?;
+            self.finish();
+            Ok(())
+        }
 
-            Ok(png.to_vec())
+        fn render_svg(&mut self) -> Result<Vec<u8>, RenderError> {
+            let svg = self.compile_to_svg()?;
+            self.finish();
+            Ok(svg)
         }
     }
 
@@ -399,3 +2732,1028 @@ pub mod native {
     //     }
     // }
 }
+
+#[cfg(test)]
+mod tests {
+
+    mod data_uri {
+        use crate::render::{to_data_uri, RenderFormat};
+
+        #[test]
+        fn to_data_uri_png() {
+            assert_eq!(
+                to_data_uri(b"\x89PNG", RenderFormat::Png),
+                "data:image/png;base64,iVBORw=="
+            );
+            assert_eq!(
+                to_data_uri(&[], RenderFormat::Jpeg { quality: 80 }),
+                "data:image/jpeg;base64,"
+            );
+        }
+    }
+
+    mod options {
+        use std::path::PathBuf;
+
+        use crate::render::{RenderError, RenderOptions, Resolution};
+
+        #[test]
+        fn resolution() {
+            let mut options = RenderOptions::new(Some(3.0), None);
+            assert_eq!((options.scale(), options.zoom()), (3.0, 1.0));
+
+            options.set_resolution(Resolution::Dpi(288.0));
+            assert_eq!((options.scale(), options.zoom()), (1.0, 3.0));
+        }
+
+        #[test]
+        fn scale_validation() {
+            assert_eq!(RenderOptions::new(Some(0.0), None).scale(), 0.1);
+            assert_eq!(RenderOptions::new(Some(10000.0), None).scale(), 50.0);
+
+            let mut options = RenderOptions::new(Some(f32::NAN), None);
+            assert!(matches!(
+                options.validate(),
+                Err(RenderError::InvalidOptions(_))
+            ));
+
+            options.set_resolution(Resolution::Dpi(f32::INFINITY));
+            assert!(options.validate().is_err());
+
+            options.set_resolution(Resolution::Scale(2.0));
+            assert!(options.validate().is_ok());
+            assert!(RenderOptions::new(None, Some(f32::NAN)).validate().is_err());
+
+            for invalid in [0, 9, u32::MAX] {
+                options.set_supersample(invalid);
+                assert!(matches!(
+                    options.validate(),
+                    Err(RenderError::InvalidOptions(_))
+                ));
+            }
+            options.set_supersample(8);
+            assert!(options.validate().is_ok());
+        }
+
+        #[test]
+        fn ghostscript_lib() {
+            let mut options = RenderOptions::default();
+            assert_eq!(options.ghostscript_lib(), None);
+
+            options.set_ghostscript_lib(Some(PathBuf::from("/opt/gs/libgs.so")));
+            assert_eq!(
+                options.ghostscript_lib(),
+                Some(&PathBuf::from("/opt/gs/libgs.so"))
+            );
+        }
+
+        #[cfg(feature = "serde")]
+        #[test]
+        fn serde_roundtrip() {
+            use crate::{document::DocumentOptions, tex::Color};
+
+            let mut options = RenderOptions::new(Some(3.0), None);
+            options.set_background(crate::render::Background::Solid(255, 255, 255, 255));
+
+            let json = serde_json::to_string(&options).unwrap();
+            let back: RenderOptions = serde_json::from_str(&json).unwrap();
+            assert_eq!(back.resolution(), Resolution::Scale(3.0));
+            assert_eq!(back.margin(), options.margin());
+            assert_eq!(back.background(), options.background());
+
+            let partial: RenderOptions = serde_json::from_str("{}").unwrap();
+            assert_eq!(partial.resolution(), Resolution::default());
+
+            let document = DocumentOptions {
+                text_color: Color::Rgb(0x12, 0x34, 0x56),
+                ..Default::default()
+            };
+            let json = serde_json::to_string(&document).unwrap();
+            assert_eq!(
+                serde_json::from_str::<DocumentOptions>(&json).unwrap(),
+                document
+            );
+        }
+    }
+
+    mod post_process {
+        use crate::{
+            document::Document,
+            render::{
+                post_process::{
+                    apply_filter, apply_transform, content_bounds, crop, draw_bbox, embed_source,
+                    embed_srgb, encode, encode_to, flatten, pad, png_dimensions, rasterize,
+                    read_embedded_source, strip_svg_metadata, tile, SheetLayout, SheetRect,
+                },
+                Background, Fit, ImageFilter, OutputTransform, RenderError, RenderFormat,
+                RenderOptions,
+            },
+        };
+
+        #[test]
+        fn estimate_output_size_kb() {
+            let options = RenderOptions::default();
+            let simple = Document::new("x");
+            let complex =
+                Document::new(r#"\sum_{k=0}^{\infty} \frac{x^k}{k!} = \int_0^1 e^{x t} \, dt"#);
+
+            assert!(
+                complex.estimate_output_size_kb(&options)
+                    > simple.estimate_output_size_kb(&options)
+            );
+        }
+
+        #[test]
+        fn rasterize_background() {
+            let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="8" height="8">
+                <rect x="3" y="3" width="2" height="2" fill="black"/>
+            </svg>"#;
+            let tree = usvg::Tree::from_str(svg, &usvg::Options::default().to_ref()).unwrap();
+
+            let mut options = RenderOptions::default();
+            let transparent = rasterize(&tree, &options).unwrap();
+            assert_eq!(transparent.pixel(0, 0).unwrap().alpha(), 0);
+
+            options.set_background(Background::Solid(32, 32, 32, 255));
+            let solid = rasterize(&tree, &options).unwrap();
+            assert_eq!(solid.pixel(0, 0).unwrap().alpha(), 255);
+        }
+
+        #[test]
+        fn rasterize_fit() {
+            let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="8" height="4">
+                <rect width="8" height="4" fill="black"/>
+            </svg>"#;
+            let tree = usvg::Tree::from_str(svg, &usvg::Options::default().to_ref()).unwrap();
+
+            let mut options = RenderOptions::new(None, Some(0.0));
+            options.set_fit(Some(Fit::Width(32)));
+            let pixmap = rasterize(&tree, &options).unwrap();
+            assert_eq!((pixmap.width(), pixmap.height()), (32, 16));
+
+            options.set_fit(Some(Fit::Height(8)));
+            let pixmap = rasterize(&tree, &options).unwrap();
+            assert_eq!((pixmap.width(), pixmap.height()), (16, 8));
+        }
+
+        #[test]
+        fn filter_grayscale() {
+            let mut pixmap = tiny_skia::Pixmap::new(2, 2).unwrap();
+            pixmap.fill(tiny_skia::Color::from_rgba8(200, 50, 10, 255));
+
+            let gray = apply_filter(&pixmap, ImageFilter::Grayscale);
+            for pixel in gray.pixels() {
+                assert_eq!(pixel.red(), pixel.green());
+                assert_eq!(pixel.green(), pixel.blue());
+                assert_eq!(pixel.alpha(), 255);
+            }
+
+            let mono = apply_filter(&pixmap, ImageFilter::Threshold(128));
+            assert_eq!(mono.pixel(0, 0).unwrap().red(), 0);
+        }
+
+        #[test]
+        fn transform_rotate90() {
+            // 3x2, red in the top-left and blue in the top-right corner.
+            let mut pixmap = tiny_skia::Pixmap::new(3, 2).unwrap();
+            let red = tiny_skia::ColorU8::from_rgba(255, 0, 0, 255).premultiply();
+            let blue = tiny_skia::ColorU8::from_rgba(0, 0, 255, 255).premultiply();
+            pixmap.pixels_mut()[0] = red;
+            pixmap.pixels_mut()[2] = blue;
+
+            let rotated = apply_transform(&pixmap, OutputTransform::Rotate90);
+            assert_eq!((rotated.width(), rotated.height()), (2, 3));
+            assert_eq!(rotated.pixel(1, 0), Some(red));
+            assert_eq!(rotated.pixel(1, 2), Some(blue));
+            assert_eq!(rotated.pixel(0, 0).unwrap().alpha(), 0);
+
+            let back = apply_transform(&rotated, OutputTransform::Rotate270);
+            assert_eq!(back, pixmap);
+
+            let flipped = apply_transform(&pixmap, OutputTransform::FlipH);
+            assert_eq!((flipped.width(), flipped.height()), (3, 2));
+            assert_eq!(flipped.pixel(0, 0), Some(blue));
+            assert_eq!(flipped.pixel(2, 0), Some(red));
+        }
+
+        #[test]
+        fn rasterize_supersample() {
+            let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="8" height="4">
+                <circle cx="4" cy="2" r="1.5" fill="black"/>
+            </svg>"#;
+            let tree = usvg::Tree::from_str(svg, &usvg::Options::default().to_ref()).unwrap();
+
+            let mut options = RenderOptions::new(None, Some(0.0));
+            let plain = rasterize(&tree, &options).unwrap();
+
+            options.set_supersample(4);
+            let supersampled = rasterize(&tree, &options).unwrap();
+
+            assert_eq!(
+                (supersampled.width(), supersampled.height()),
+                (plain.width(), plain.height())
+            );
+        }
+
+        #[test]
+        fn rasterize_empty() {
+            // What `dvisvgm` produces for a document that compiles to an empty page.
+            let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="0pt" height="0pt" viewBox="0 0 0 0"/>"#;
+
+            let result = usvg::Tree::from_str(svg, &usvg::Options::default().to_ref())
+                .map_err(RenderError::from)
+                .and_then(|tree| rasterize(&tree, &RenderOptions::default()));
+
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn crop_to_content() {
+            let mut pixmap = tiny_skia::Pixmap::new(20, 20).unwrap();
+            let mut paint = tiny_skia::Paint::default();
+            paint.set_color_rgba8(0, 0, 0, 255);
+            pixmap.fill_rect(
+                tiny_skia::Rect::from_xywh(5.0, 5.0, 4.0, 4.0).unwrap(),
+                &paint,
+                tiny_skia::Transform::identity(),
+                None,
+            );
+
+            assert_eq!(
+                content_bounds(&pixmap, Background::Transparent),
+                Some((5, 5, 8, 8))
+            );
+
+            let cropped = crop(&pixmap, Background::Transparent, 1);
+            assert_eq!((cropped.width(), cropped.height()), (6, 6));
+
+            let clamped = crop(&pixmap, Background::Transparent, 12);
+            assert_eq!((clamped.width(), clamped.height()), (20, 20));
+        }
+
+        #[test]
+        fn debug_bbox() {
+            let mut pixmap = tiny_skia::Pixmap::new(10, 10).unwrap();
+            let black = tiny_skia::ColorU8::from_rgba(0, 0, 0, 255).premultiply();
+            for (x, y) in [(4, 4), (5, 5)] {
+                pixmap.pixels_mut()[y * 10 + x] = black;
+            }
+
+            let outlined = draw_bbox(&pixmap, Background::Transparent);
+            let pixel = |x: usize, y: usize| outlined.pixels()[y * 10 + x];
+            let red = tiny_skia::ColorU8::from_rgba(255, 0, 0, 255).premultiply();
+
+            for (x, y) in [(3, 3), (6, 3), (3, 6), (6, 6), (3, 4), (5, 6)] {
+                assert_eq!(pixel(x, y), red, "({}, {})", x, y);
+            }
+            assert_eq!(pixel(4, 4), black);
+            assert_eq!(pixel(5, 4).alpha(), 0);
+            assert_eq!(pixel(2, 2).alpha(), 0);
+            assert_eq!(pixel(7, 7).alpha(), 0);
+        }
+
+        #[test]
+        fn pad_margin() {
+            let mut pixmap = tiny_skia::Pixmap::new(2, 2).unwrap();
+            pixmap.fill(tiny_skia::Color::BLACK);
+
+            let padded = pad(&pixmap, Background::Transparent, 3);
+            assert_eq!((padded.width(), padded.height()), (8, 8));
+            assert_eq!(padded.pixel(0, 0).unwrap().alpha(), 0);
+            assert_eq!(padded.pixel(3, 3).unwrap().alpha(), 255);
+        }
+
+        #[test]
+        fn strip_metadata() {
+            let svg = br#"<?xml version='1.0'?>
+<!-- This file was generated by dvisvgm 3.1 -->
+<svg><metadata><dc:date>2024-01-01</dc:date></metadata><path d='M0 0'/><!-- x --></svg>"#;
+
+            assert_eq!(
+                strip_svg_metadata(svg),
+                b"<?xml version='1.0'?>\n\n<svg><path d='M0 0'/></svg>".to_vec()
+            );
+
+            let plain = b"<svg><path d='M0 0'/></svg>";
+            assert_eq!(strip_svg_metadata(plain), plain.to_vec());
+        }
+
+        #[test]
+        fn embedded_source() {
+            // Signature and a 1x1 RGBA IHDR, enough for the chunk to be placed after.
+            let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+            png.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+            png.extend_from_slice(&[0x1f, 0x15, 0xc4, 0x89]);
+            png.extend_from_slice(b"\x00\x00\x00\x00IEND\xae\x42\x60\x82");
+
+            assert_eq!(read_embedded_source(&png), None);
+
+            let tex = r#"\[ x^2 + \alpha \]"#;
+            let embedded = embed_source(&png, tex);
+            assert_eq!(read_embedded_source(&embedded), Some(tex.to_string()));
+            assert_eq!(&embedded[33..37], &[0, 0, 0, 7 + tex.len() as u8]);
+            assert!(embedded.ends_with(b"IEND\xae\x42\x60\x82"));
+
+            assert_eq!(embed_source(b"not a png", tex), b"not a png");
+
+            let tagged = embed_srgb(&png);
+            assert_eq!(&tagged[33..37], &[0, 0, 0, 1]);
+            assert_eq!(&tagged[37..42], b"sRGB\x00");
+            assert_eq!(&tagged[42..46], &[0xae, 0xce, 0x1c, 0xe9]);
+            assert_eq!(tagged.len(), png.len() + 13);
+            assert_eq!(png_dimensions(&embedded), Some((1, 1)));
+            assert_eq!(png_dimensions(b"not a png"), None);
+        }
+
+        #[test]
+        fn tile_sheet() {
+            let wide = tiny_skia::Pixmap::new(3, 2).unwrap();
+            let tall = tiny_skia::Pixmap::new(2, 4).unwrap();
+            let pixmaps = [tall, wide];
+
+            let (sheet, rects) =
+                tile(&pixmaps, SheetLayout::Horizontal, Background::Transparent).unwrap();
+            assert_eq!((sheet.width(), sheet.height()), (5, 4));
+            assert_eq!(
+                rects,
+                [
+                    SheetRect {
+                        x: 0,
+                        y: 0,
+                        width: 2,
+                        height: 4
+                    },
+                    SheetRect {
+                        x: 2,
+                        y: 1,
+                        width: 3,
+                        height: 2
+                    },
+                ]
+            );
+
+            let (sheet, rects) =
+                tile(&pixmaps, SheetLayout::Vertical, Background::Transparent).unwrap();
+            assert_eq!((sheet.width(), sheet.height()), (3, 6));
+            assert_eq!(
+                rects[1],
+                SheetRect {
+                    x: 0,
+                    y: 4,
+                    width: 3,
+                    height: 2
+                }
+            );
+
+            assert!(tile(&[], SheetLayout::Horizontal, Background::Transparent).is_err());
+        }
+
+        #[test]
+        fn encode_jpeg() {
+            let pixmap = tiny_skia::Pixmap::new(4, 4).unwrap();
+            let data = encode(&pixmap, RenderFormat::Jpeg { quality: 80 }).unwrap();
+
+            assert_eq!(&data[..3], &[0xFF, 0xD8, 0xFF]);
+
+            // Encoding leaves the matte to the caller.
+            let flat = flatten(&pixmap, (0, 0, 255));
+            let data = encode(&flat, RenderFormat::Jpeg { quality: 100 }).unwrap();
+            let [r, g, b] = image::load_from_memory(&data)
+                .unwrap()
+                .to_rgb8()
+                .get_pixel(0, 0)
+                .0;
+            assert!(r < 16 && g < 16 && b > 240, "{:?}", (r, g, b));
+        }
+
+        #[test]
+        fn flatten_matte() {
+            let mut pixmap = tiny_skia::Pixmap::new(2, 1).unwrap();
+            pixmap.pixels_mut()[0] = tiny_skia::ColorU8::from_rgba(0, 0, 0, 255).premultiply();
+
+            let white = flatten(&pixmap, (255, 255, 255));
+            let ink = white.pixel(0, 0).unwrap();
+            let paper = white.pixel(1, 0).unwrap();
+            assert_eq!((ink.red(), ink.alpha()), (0, 255));
+            assert_eq!((paper.red(), paper.green(), paper.blue()), (255, 255, 255));
+            assert_eq!(paper.alpha(), 255);
+
+            let red = flatten(&pixmap, (255, 0, 0)).pixel(1, 0).unwrap();
+            assert_eq!((red.red(), red.green(), red.alpha()), (255, 0, 255));
+
+            let mut options = RenderOptions::default();
+            assert_eq!(options.matte(), (255, 255, 255));
+            options.set_matte(Some((0, 0, 255)));
+            assert_eq!(options.matte(), (0, 0, 255));
+        }
+
+        #[test]
+        fn encode_to_writer() {
+            let pixmap = tiny_skia::Pixmap::new(4, 4).unwrap();
+
+            let mut out = std::io::Cursor::new(Vec::new());
+            encode_to(&pixmap, RenderFormat::Png, &mut out).unwrap();
+
+            assert_eq!(
+                out.into_inner(),
+                encode(&pixmap, RenderFormat::Png).unwrap()
+            );
+        }
+    }
+
+    mod native {
+        use crate::render::{
+            cache::RenderCache,
+            native::{
+                find_program, missing_programs, parse_dvisvgm_depth, parse_pdflatex_logs,
+                parse_pdflatex_warnings, run, texinputs, LogRecord, RenderInstanceNative,
+            },
+            CancelToken, RenderBackend, RenderError, RenderInstance, RenderOptions, RenderStage,
+            Toolchain,
+        };
+
+        #[test]
+        fn dvisvgm_depth() {
+            let output = "pre-processing DVI file (format version 2)\n\
+                          processing page 1\n  \
+                          computing extents based on data set by preview package (version 13.1)\n  \
+                          width=21.67pt, height=7.53pt, depth=2.49pt\n";
+
+            assert_eq!(parse_dvisvgm_depth(output), Some(2.49));
+            assert_eq!(parse_dvisvgm_depth("processing page 1\n"), None);
+        }
+
+        #[test]
+        fn clean() {
+            let root = std::env::temp_dir().join(format!("teximex-clean-{}", std::process::id()));
+            std::fs::create_dir_all(&root).unwrap();
+            for file in ["texput.tex", "texput2.svg", "out.png", "keep.txt"] {
+                std::fs::write(root.join(file), b"").unwrap();
+            }
+
+            let instance = RenderInstance::new_with_options(RenderOptions::default())
+                .load(crate::document::Document::new("x".to_string()));
+            let native = RenderInstanceNative::new(&root, instance).with_cleanup_on_drop();
+            drop(native);
+
+            assert!(!root.join("texput.tex").exists());
+            assert!(!root.join("out.png").exists());
+            assert!(root.join("keep.txt").exists());
+
+            std::fs::remove_dir_all(root).unwrap();
+        }
+
+        #[test]
+        #[ignore = "requires a TeX installation"]
+        fn deterministic() {
+            let mut options = RenderOptions::default();
+            options.set_deterministic(true);
+            let render = || {
+                let root = std::env::temp_dir()
+                    .join(format!("teximex-deterministic-{}", std::process::id()));
+                std::fs::create_dir_all(&root).unwrap();
+                let instance = RenderInstance::new_with_options(options.clone())
+                    .load(crate::document::Document::new(r#"\[ x^2 \]"#.to_string()));
+                let result = RenderInstanceNative::new(&root, instance).render();
+                std::fs::remove_dir_all(root).unwrap();
+                result
+            };
+
+            assert_eq!(render().unwrap(), render().unwrap());
+        }
+
+        #[test]
+        fn cleanup_on_success() {
+            use crate::tex::TexString;
+
+            let root = std::env::temp_dir().join(format!("teximex-success-{}", std::process::id()));
+            let cache = RenderCache::in_memory(1024);
+
+            let document = crate::document::Document::new("x".to_string());
+            cache
+                .insert(
+                    RenderCache::key(&document.to_tex(), &RenderOptions::default()),
+                    b"cached",
+                )
+                .unwrap();
+
+            std::fs::create_dir_all(&root).unwrap();
+            std::fs::write(root.join("texput.tex"), b"").unwrap();
+
+            let instance =
+                RenderInstance::new_with_options(RenderOptions::default()).load(document);
+            let mut native = RenderInstanceNative::new(&root, instance)
+                .with_cache(cache)
+                .with_cleanup_on_success();
+            assert_eq!(native.tex_path(), root.join("texput.tex"));
+
+            native.render().unwrap();
+            drop(native);
+            assert!(!root.join("texput.tex").exists());
+
+            std::fs::remove_dir_all(root).unwrap();
+        }
+
+        #[test]
+        fn logs_per_render() {
+            use crate::tex::TexString;
+
+            let document = crate::document::Document::new("x".to_string());
+            let cache = RenderCache::in_memory(1024);
+            cache
+                .insert(
+                    RenderCache::key(&document.to_tex(), &RenderOptions::default()),
+                    b"cached",
+                )
+                .unwrap();
+
+            let instance =
+                RenderInstance::new_with_options(RenderOptions::default()).load(document);
+            let mut native =
+                RenderInstanceNative::new(std::env::temp_dir(), instance).with_cache(cache);
+
+            native.logs.push(LogRecord::Warning("stale".to_string()));
+            for _ in 0..2 {
+                native.render().unwrap();
+                assert!(native.logs.is_empty());
+                assert_eq!(native.last_dimensions(), None);
+            }
+        }
+
+        #[test]
+        #[ignore = "requires a TeX installation"]
+        fn warnings_per_render() {
+            let root = std::env::temp_dir().join(format!("teximex-logs-{}", std::process::id()));
+            std::fs::create_dir_all(&root).unwrap();
+            let instance = RenderInstance::new_with_options(RenderOptions::default()).load(
+                crate::document::Document::new(r#"see \ref{missing}"#.to_string()),
+            );
+            let mut native = RenderInstanceNative::new(&root, instance).with_cleanup_on_drop();
+
+            let first = native.render_with_metadata().unwrap();
+            let second = native.render_with_metadata().unwrap();
+            assert_eq!(first.warnings, second.warnings);
+            assert_eq!(native.warnings().count(), second.warnings.len());
+
+            drop(native);
+            std::fs::remove_dir_all(root).unwrap();
+        }
+
+        #[test]
+        fn batch_cleanup() {
+            let root = std::env::temp_dir().join(format!("teximex-batch-{}", std::process::id()));
+            // Subdirectories the user already had are left alone.
+            std::fs::create_dir_all(root.join("0")).unwrap();
+            let documents = [
+                crate::document::Document::new("x".to_string()),
+                crate::document::Document::new(r#"\input{/etc/passwd}"#.to_string()),
+            ];
+
+            let results =
+                RenderInstanceNative::render_batch(&root, &documents, &RenderOptions::default());
+            assert!(matches!(results[1], Err(RenderError::Unsafe(_))));
+            let entries = std::fs::read_dir(&root)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>();
+            assert_eq!(entries, ["0"]);
+
+            std::fs::remove_dir_all(root).unwrap();
+        }
+
+        #[test]
+        fn max_width_preview() {
+            let mut document_options = crate::document::DocumentOptions {
+                max_width_cm: Some(5.0),
+                ..Default::default()
+            };
+            let mut options = RenderOptions::default();
+            options.set_toolchain(Toolchain::Pdflatex);
+            let instance = RenderInstance::new_with_options(options.clone()).load(
+                crate::document::Document::new_with_options(
+                    "x".to_string(),
+                    document_options.clone(),
+                ),
+            );
+            let mut native = RenderInstanceNative::new(std::env::temp_dir(), instance);
+
+            let tex = String::from_utf8(native.create_tex()).unwrap();
+            assert!(tex.contains(
+                "\\begin{preview}\\noindent\\begin{varwidth}{5cm}\nx\n\\end{varwidth}\\end{preview}"
+            ));
+            assert_eq!(tex.matches(r#"\usepackage{varwidth}"#).count(), 1);
+            assert_eq!(tex.matches(r#"\begin{varwidth}"#).count(), 1);
+
+            document_options.max_width_cm = Some(f32::NAN);
+            native.instance = RenderInstance::new_with_options(options).load(
+                crate::document::Document::new_with_options("x".to_string(), document_options),
+            );
+            assert!(matches!(
+                native.render(),
+                Err(RenderError::InvalidOptions(_))
+            ));
+        }
+
+        #[test]
+        fn job_name() {
+            let root = std::env::temp_dir().join(format!("teximex-job-{}", std::process::id()));
+            std::fs::create_dir_all(&root).unwrap();
+            for file in [
+                "formula.tex",
+                "formula2.svg",
+                "formula-out.png",
+                "texput.tex",
+            ] {
+                std::fs::write(root.join(file), b"").unwrap();
+            }
+
+            let mut options = RenderOptions::default();
+            options.set_job_name(Some("formula".to_string()));
+            let instance = RenderInstance::new_with_options(options)
+                .load(crate::document::Document::new("x".to_string()));
+            let native = RenderInstanceNative::new(&root, instance);
+
+            let commands = native.render_commands();
+            assert!(commands[0].contains(&"-jobname=formula".to_string()));
+            assert!(commands[0].contains(&"formula.tex".to_string()));
+            assert!(commands[1].contains(&"formula2.dvi".to_string()));
+
+            native.clean().unwrap();
+            assert!(!root.join("formula.tex").exists());
+            assert!(!root.join("formula2.svg").exists());
+            assert!(!root.join("formula-out.png").exists());
+            assert!(root.join("texput.tex").exists());
+
+            std::fs::remove_dir_all(root).unwrap();
+
+            let mut options = RenderOptions::default();
+            for invalid in ["", "two words", "../escape", "-shell-escape"] {
+                options.set_job_name(Some(invalid.to_string()));
+                assert!(
+                    matches!(options.validate(), Err(RenderError::InvalidOptions(_))),
+                    "{:?}",
+                    invalid
+                );
+            }
+        }
+
+        #[test]
+        fn cancelled() {
+            let token = CancelToken::new();
+            let instance = RenderInstance::new_with_options(RenderOptions::default())
+                .load(crate::document::Document::new("x".to_string()));
+            let mut native = RenderInstanceNative::new(std::env::temp_dir(), instance)
+                .with_cancel_token(token.clone());
+
+            token.clone().cancel();
+            assert!(token.is_cancelled());
+            assert!(matches!(native.render(), Err(RenderError::Cancelled)));
+            assert!(matches!(native.render_svg(), Err(RenderError::Cancelled)));
+            assert!(matches!(native.render_image(), Err(RenderError::Cancelled)));
+        }
+
+        #[test]
+        fn sandbox() {
+            use crate::tex::TexString;
+
+            let instance = RenderInstance::new_with_options(RenderOptions::default()).load(
+                crate::document::Document::new(r#"\input{/etc/passwd}"#.to_string()),
+            );
+            let key = RenderCache::key(&instance.document().to_tex(), &RenderOptions::default());
+            let cache = RenderCache::in_memory(1024);
+            cache.insert(key, b"cached").unwrap();
+            let mut native =
+                RenderInstanceNative::new(std::env::temp_dir(), instance).with_cache(cache);
+
+            match native.render() {
+                Err(RenderError::Unsafe(blocked)) => assert_eq!(blocked, r#"\input"#),
+                other => panic!("expected RenderError::Unsafe, got {:?}", other),
+            }
+            assert!(matches!(native.render_pdf(), Err(RenderError::Unsafe(_))));
+        }
+
+        #[test]
+        fn usvg_options_bypass_cache() {
+            use crate::tex::TexString;
+
+            let instance = RenderInstance::new_with_options(RenderOptions::default())
+                .load(crate::document::Document::new("x".to_string()));
+            let key = RenderCache::key(&instance.document().to_tex(), &RenderOptions::default());
+            let cache = RenderCache::in_memory(1024);
+            cache.insert(key, b"cached").unwrap();
+            let mut native = RenderInstanceNative::new(std::env::temp_dir(), instance)
+                .with_cache(cache.clone())
+                .with_usvg_options(usvg::Options::default());
+
+            assert!(!matches!(native.render(), Ok(data) if data == b"cached"));
+            assert_eq!(cache.stats().hits + cache.stats().misses, 0);
+        }
+
+        #[test]
+        #[cfg(unix)]
+        fn texinputs_path() {
+            use std::{ffi::OsStr, path::Path};
+
+            let dir = Path::new("/srv/styles");
+            assert_eq!(texinputs(dir, None), OsStr::new("/srv/styles:"));
+            assert_eq!(
+                texinputs(dir, Some(OsStr::new("/home/me/tex:"))),
+                OsStr::new("/srv/styles:/home/me/tex:")
+            );
+        }
+
+        #[test]
+        fn progress() {
+            use crate::tex::TexString;
+
+            let stages = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let root =
+                std::env::temp_dir().join(format!("teximex-progress-{}", std::process::id()));
+            let cache = RenderCache::with_dir(root.join("cache"));
+
+            let document = crate::document::Document::new("x".to_string());
+            let options = RenderOptions::default();
+            cache
+                .insert(RenderCache::key(&document.to_tex(), &options), b"cached")
+                .unwrap();
+
+            let instance = RenderInstance::new_with_options(options).load(document);
+            let recorded = stages.clone();
+            let mut native = RenderInstanceNative::new(&root, instance)
+                .with_cache(cache)
+                .on_progress(move |stage| recorded.lock().unwrap().push(stage));
+
+            assert_eq!(native.render().unwrap(), b"cached");
+            assert_eq!(*stages.lock().unwrap(), vec![RenderStage::Done]);
+
+            // A render that fails before compiling reports nothing.
+            stages.lock().unwrap().clear();
+            native.instance = RenderInstance::new_with_options(RenderOptions::default()).load(
+                crate::document::Document::new(r#"\input{/etc/passwd}"#.to_string()),
+            );
+            assert!(native.render().is_err());
+            assert!(stages.lock().unwrap().is_empty());
+
+            std::fs::remove_dir_all(root).unwrap();
+        }
+
+        #[test]
+        fn render_commands() {
+            let instance = RenderInstance::new_with_options(RenderOptions::new(Some(2.0), None))
+                .load(crate::document::Document::new("x".to_string()));
+            let mut native = RenderInstanceNative::new(std::env::temp_dir(), instance);
+
+            assert_eq!(
+                native.render_commands(),
+                vec![
+                    vec![
+                        "pdflatex",
+                        "-jobname=texput",
+                        "-output-format=dvi",
+                        "-interaction=nonstopmode",
+                        "-no-shell-escape",
+                        "texput.tex",
+                    ],
+                    vec!["dvisvgm", "texput2.dvi", "--no-fonts", "--scale=2"],
+                ]
+            );
+
+            native.instance.options.set_toolchain(Toolchain::Pdflatex);
+            assert_eq!(
+                native.render_commands(),
+                vec![
+                    vec![
+                        "pdflatex",
+                        "-jobname=texput",
+                        "-output-format=pdf",
+                        "-interaction=nonstopmode",
+                        "-no-shell-escape",
+                        "texput.tex",
+                    ],
+                    vec!["pdf2svg", "texput.pdf", "texput2.svg"],
+                ]
+            );
+
+            let mut options = crate::document::DocumentOptions {
+                sandbox: false,
+                ..Default::default()
+            };
+            native.instance = RenderInstance::new_with_options(RenderOptions::default()).load(
+                crate::document::Document::new_with_options("x".to_string(), options.clone()),
+            );
+            assert!(!native.render_commands()[0].contains(&"-no-shell-escape".to_string()));
+
+            options.externalize = true;
+            native.instance = RenderInstance::new_with_options(RenderOptions::default()).load(
+                crate::document::Document::new_with_options("x".to_string(), options),
+            );
+            assert!(native.render_commands()[0].contains(&"-shell-escape".to_string()));
+        }
+
+        #[test]
+        fn missing_dependencies() {
+            assert_eq!(find_program("teximex-no-such-program"), None);
+            assert_eq!(
+                missing_programs(&["teximex-no-such-program"]),
+                vec!["teximex-no-such-program"]
+            );
+            #[cfg(unix)]
+            assert_eq!(
+                missing_programs(&["sh", "teximex-no-such-program"]),
+                vec!["teximex-no-such-program"]
+            );
+
+            let instance = RenderInstance::new_with_options(RenderOptions::default())
+                .load(crate::document::Document::new("x".to_string()));
+            let native = RenderInstanceNative::new(std::env::temp_dir(), instance);
+
+            if let Err(missing) = native.check_dependencies() {
+                assert!(missing
+                    .iter()
+                    .all(|program| ["pdflatex", "dvisvgm"].contains(&program.as_str())));
+            }
+        }
+
+        #[test]
+        #[cfg(unix)]
+        fn timeout() {
+            use std::{
+                process::Command,
+                time::{Duration, Instant},
+            };
+
+            let start = Instant::now();
+            let killed = run(
+                Command::new("sleep").arg("10"),
+                Some(Duration::from_millis(50)),
+            )
+            .unwrap();
+            assert!(killed.is_none());
+            assert!(start.elapsed() < Duration::from_secs(5));
+
+            let output = run(
+                Command::new("echo").arg("done"),
+                Some(Duration::from_secs(10)),
+            )
+            .unwrap()
+            .unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"done\n");
+
+            let mut options = RenderOptions::default();
+            assert_eq!(options.timeout(), None);
+            options
+                .set_timeout(Some(Duration::from_millis(50)))
+                .set_max_retries(1);
+            let instance = RenderInstance::new_with_options(options)
+                .load(crate::document::Document::new("x".to_string()));
+            let mut native = RenderInstanceNative::new(std::env::temp_dir(), instance);
+
+            match native.output("sleep", |command| {
+                command.arg("10");
+            }) {
+                Err(RenderError::Timeout { program, timeout }) => {
+                    assert_eq!(program, "sleep");
+                    assert_eq!(timeout, Duration::from_millis(100));
+                }
+                other => panic!("expected RenderError::Timeout, got {:?}", other),
+            }
+            assert!(matches!(native.logs.as_slice(), [LogRecord::Retry { .. }]));
+        }
+
+        #[test]
+        fn pdflatex_warnings() {
+            let output = "(./texput.tex\nLaTeX Warning: Reference `eq' on page 1 undefined.\n\n\
+                Overfull \\hbox (12.0pt too wide) in paragraph at lines 5--6\n\
+                Package siunitx Warning: Deprecated option.\n) )\n";
+            let warnings = parse_pdflatex_warnings(output);
+
+            assert_eq!(
+                warnings,
+                vec![
+                    "LaTeX Warning: Reference `eq' on page 1 undefined.",
+                    r#"Overfull \hbox (12.0pt too wide) in paragraph at lines 5--6"#,
+                    "Package siunitx Warning: Deprecated option.",
+                ]
+            );
+
+            let warning = LogRecord::Warning(warnings[0].clone());
+            assert!(warning.is_warning() && !warning.is_error());
+            assert!(!LogRecord::Pdflatex(Vec::new()).is_error());
+        }
+
+        #[test]
+        fn display_log_record() {
+            let logs = parse_pdflatex_logs(
+                "! Undefined control sequence.\nl.5 \\foo\n\n! Missing $ inserted.\nl.7 x^2\n",
+            )
+            .unwrap();
+
+            assert_eq!(
+                logs[0].to_string(),
+                "l.5: Undefined control sequence. \u{2014} \\foo"
+            );
+            assert_eq!(
+                LogRecord::Stderr {
+                    program: "dvisvgm".to_string(),
+                    output: "ERROR: file not found\n".to_string(),
+                }
+                .to_string(),
+                "dvisvgm: ERROR: file not found"
+            );
+            assert_eq!(
+                LogRecord::Retry {
+                    program: "pdflatex".to_string(),
+                    timeout: std::time::Duration::from_secs(10),
+                }
+                .to_string(),
+                "pdflatex: timed out, retrying with 10s"
+            );
+            assert_eq!(
+                LogRecord::Warning("LaTeX Warning: Reference `eq' undefined.".to_string())
+                    .to_string(),
+                "warning: LaTeX Warning: Reference `eq' undefined."
+            );
+            assert_eq!(
+                LogRecord::Timing {
+                    stage: "compile",
+                    duration: std::time::Duration::from_millis(1234),
+                }
+                .to_string(),
+                "compile: 1.2s"
+            );
+            assert_eq!(
+                LogRecord::Pdflatex(logs).to_string(),
+                "l.5: Undefined control sequence. \u{2014} \\foo\nl.7: Missing $ inserted. \u{2014} x^2"
+            );
+        }
+    }
+
+    mod cache {
+        use crate::render::{
+            cache::{CacheStats, RenderCache},
+            RenderOptions,
+        };
+
+        #[test]
+        fn roundtrip() {
+            let dir = std::env::temp_dir().join(format!("teximex-cache-{}", std::process::id()));
+            let cache = RenderCache::with_dir(&dir);
+
+            let options = RenderOptions::default();
+            let key = RenderCache::key("x^2", &options);
+            assert_ne!(
+                key,
+                RenderCache::key("x^2", &RenderOptions::new(Some(4.0), None))
+            );
+            assert_ne!(
+                key,
+                RenderCache::key("x^2", &RenderOptions::new(None, Some(0.0)))
+            );
+            assert_ne!(
+                key,
+                RenderCache::key("x^2", RenderOptions::default().set_auto_crop(true))
+            );
+            // Pinned, entries on disk must stay valid across Rust releases.
+            assert_eq!(
+                key.to_string(),
+                "32b73ad8627103ee2d06d1a68b5764c5ad5df2f6c2f79e2f22806c27f3e8bcb8"
+            );
+
+            assert_eq!(cache.get(key), None);
+            cache.insert(key, b"png").unwrap();
+            assert_eq!(cache.get(key), Some(b"png".to_vec()));
+
+            cache.clear().unwrap();
+            assert_eq!(cache.get(key), None);
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn in_memory_lru() {
+            let cache = RenderCache::in_memory(10);
+            assert_eq!(cache.dir(), None);
+            let [a, b, c, d] =
+                ["a", "b", "c", "d"].map(|tex| RenderCache::key(tex, &RenderOptions::default()));
+
+            cache.insert(a, b"aaaa").unwrap();
+            cache.insert(b, b"bbbb").unwrap();
+            assert_eq!(cache.get(a), Some(b"aaaa".to_vec()));
+
+            // `b` is now the least recently used and makes room for `c`.
+            cache.insert(c, b"cccc").unwrap();
+            assert_eq!(cache.get(b), None);
+            assert_eq!(cache.get(a), Some(b"aaaa".to_vec()));
+            assert_eq!(cache.get(c), Some(b"cccc".to_vec()));
+
+            cache.insert(d, b"too large to fit").unwrap();
+            assert_eq!(cache.get(d), None);
+
+            assert_eq!(cache.clone().stats(), CacheStats { hits: 3, misses: 2 });
+
+            cache.clear().unwrap();
+            assert_eq!(cache.get(a), None);
+        }
+    }
+}
@@ -16,13 +16,12 @@ impl TexString for String {
     }
 }
 
-/*
+/// Represents a `\usepackage[arbitrary...]{main}` (La)TeX command.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct Usepackage<T: TexString> {
     arbitrary: Vec<T>,
     main: T,
 }
-*/
 
 /*
 fn parse_usepackage(mut package: Usepackage<String>, string: String) -> (Usepackage<String>, String) {
@@ -56,30 +55,409 @@ impl Usepackage<String> {
 */
 
 /// Represents a `\color` (La)TeX command.
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Copy)]
+///
+/// Named variants other than [`Color::Black`] and [`Color::White`] come from the `dvipsnames`
+/// option of `xcolor`.
+///
+/// Not `Copy`, since [`Color::Named`] owns its name. Clone it where it used to be copied.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Color {
+    /// `\color{Apricot}`
+    Apricot,
+    /// `\color{Aquamarine}`
+    Aquamarine,
+    /// `\color{Bittersweet}`
+    Bittersweet,
     /// `\color{black}`
+    #[default]
     Black,
-    /// `color{white}`
+    /// `\color{Blue}`
+    Blue,
+    /// `\color{BlueGreen}`
+    BlueGreen,
+    /// `\color{BlueViolet}`
+    BlueViolet,
+    /// `\color{BrickRed}`
+    BrickRed,
+    /// `\color{Brown}`
+    Brown,
+    /// `\color{BurntOrange}`
+    BurntOrange,
+    /// `\color{CadetBlue}`
+    CadetBlue,
+    /// `\color{CarnationPink}`
+    CarnationPink,
+    /// `\color{Cerulean}`
+    Cerulean,
+    /// `\color{CornflowerBlue}`
+    CornflowerBlue,
+    /// `\color{Cyan}`
+    Cyan,
+    /// `\color{Dandelion}`
+    Dandelion,
+    /// `\color{DarkOrchid}`
+    DarkOrchid,
+    /// `\color{Emerald}`
+    Emerald,
+    /// `\color{ForestGreen}`
+    ForestGreen,
+    /// `\color{Fuchsia}`
+    Fuchsia,
+    /// `\color{Goldenrod}`
+    Goldenrod,
+    /// `\color{Gray}`
+    Gray,
+    /// `\color{Green}`
+    Green,
+    /// `\color{GreenYellow}`
+    GreenYellow,
+    /// `\color{JungleGreen}`
+    JungleGreen,
+    /// `\color{Lavender}`
+    Lavender,
+    /// `\color{LimeGreen}`
+    LimeGreen,
+    /// `\color{Magenta}`
+    Magenta,
+    /// `\color{Mahogany}`
+    Mahogany,
+    /// `\color{Maroon}`
+    Maroon,
+    /// `\color{Melon}`
+    Melon,
+    /// `\color{MidnightBlue}`
+    MidnightBlue,
+    /// `\color{Mulberry}`
+    Mulberry,
+    /// `\color{NavyBlue}`
+    NavyBlue,
+    /// `\color{OliveGreen}`
+    OliveGreen,
+    /// `\color{Orange}`
+    Orange,
+    /// `\color{OrangeRed}`
+    OrangeRed,
+    /// `\color{Orchid}`
+    Orchid,
+    /// `\color{Peach}`
+    Peach,
+    /// `\color{Periwinkle}`
+    Periwinkle,
+    /// `\color{PineGreen}`
+    PineGreen,
+    /// `\color{Plum}`
+    Plum,
+    /// `\color{ProcessBlue}`
+    ProcessBlue,
+    /// `\color{Purple}`
+    Purple,
+    /// `\color{RawSienna}`
+    RawSienna,
+    /// `\color{Red}`
+    Red,
+    /// `\color{RedOrange}`
+    RedOrange,
+    /// `\color{RedViolet}`
+    RedViolet,
+    /// `\color{Rhodamine}`
+    Rhodamine,
+    /// `\color{RoyalBlue}`
+    RoyalBlue,
+    /// `\color{RoyalPurple}`
+    RoyalPurple,
+    /// `\color{RubineRed}`
+    RubineRed,
+    /// `\color{Salmon}`
+    Salmon,
+    /// `\color{SeaGreen}`
+    SeaGreen,
+    /// `\color{Sepia}`
+    Sepia,
+    /// `\color{SkyBlue}`
+    SkyBlue,
+    /// `\color{SpringGreen}`
+    SpringGreen,
+    /// `\color{Tan}`
+    Tan,
+    /// `\color{TealBlue}`
+    TealBlue,
+    /// `\color{Thistle}`
+    Thistle,
+    /// `\color{Turquoise}`
+    Turquoise,
+    /// `\color{Violet}`
+    Violet,
+    /// `\color{VioletRed}`
+    VioletRed,
+    /// `\color{white}`
     White,
+    /// `\color{WildStrawberry}`
+    WildStrawberry,
+    /// `\color{Yellow}`
+    Yellow,
+    /// `\color{YellowGreen}`
+    YellowGreen,
+    /// `\color{YellowOrange}`
+    YellowOrange,
+    /// `\color{name}` for an arbitrary `xcolor` colour name.
+    Named(String),
+    /// `\color[HTML]{rrggbb}`, `xcolor`'s inline colour model, rather than a `\definecolor`
+    /// that would claim a colour name the user's preamble might also define.
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    /// Every predefined named colour, in `dvipsnames` order.
+    pub const NAMED: &'static [Color] = &[
+        Color::Apricot,
+        Color::Aquamarine,
+        Color::Bittersweet,
+        Color::Black,
+        Color::Blue,
+        Color::BlueGreen,
+        Color::BlueViolet,
+        Color::BrickRed,
+        Color::Brown,
+        Color::BurntOrange,
+        Color::CadetBlue,
+        Color::CarnationPink,
+        Color::Cerulean,
+        Color::CornflowerBlue,
+        Color::Cyan,
+        Color::Dandelion,
+        Color::DarkOrchid,
+        Color::Emerald,
+        Color::ForestGreen,
+        Color::Fuchsia,
+        Color::Goldenrod,
+        Color::Gray,
+        Color::Green,
+        Color::GreenYellow,
+        Color::JungleGreen,
+        Color::Lavender,
+        Color::LimeGreen,
+        Color::Magenta,
+        Color::Mahogany,
+        Color::Maroon,
+        Color::Melon,
+        Color::MidnightBlue,
+        Color::Mulberry,
+        Color::NavyBlue,
+        Color::OliveGreen,
+        Color::Orange,
+        Color::OrangeRed,
+        Color::Orchid,
+        Color::Peach,
+        Color::Periwinkle,
+        Color::PineGreen,
+        Color::Plum,
+        Color::ProcessBlue,
+        Color::Purple,
+        Color::RawSienna,
+        Color::Red,
+        Color::RedOrange,
+        Color::RedViolet,
+        Color::Rhodamine,
+        Color::RoyalBlue,
+        Color::RoyalPurple,
+        Color::RubineRed,
+        Color::Salmon,
+        Color::SeaGreen,
+        Color::Sepia,
+        Color::SkyBlue,
+        Color::SpringGreen,
+        Color::Tan,
+        Color::TealBlue,
+        Color::Thistle,
+        Color::Turquoise,
+        Color::Violet,
+        Color::VioletRed,
+        Color::White,
+        Color::WildStrawberry,
+        Color::Yellow,
+        Color::YellowGreen,
+        Color::YellowOrange,
+    ];
+
+    /// Colours `xcolor` defines without any package options.
+    pub const XCOLOR_BASE: &'static [&'static str] = &[
+        "black",
+        "blue",
+        "brown",
+        "cyan",
+        "darkgray",
+        "gray",
+        "green",
+        "lightgray",
+        "lime",
+        "magenta",
+        "olive",
+        "orange",
+        "pink",
+        "purple",
+        "red",
+        "teal",
+        "violet",
+        "white",
+        "yellow",
+    ];
+
+    /// Look up a colour by its `xcolor` name, checking that `preamble` makes it available.
+    ///
+    /// The base `xcolor` colours are always accepted. They map to [`Color::Black`] and
+    /// [`Color::White`] where those exist, like they do when parsed, and to [`Color::Named`]
+    /// otherwise. `dvipsnames` colours need the option in the preamble and map to their
+    /// predefined variant. With `svgnames` or `x11names` loaded any name is accepted as
+    /// [`Color::Named`], since those sets are not enumerated here.
+    pub fn named(name: &str, preamble: &str) -> Result<Self, UnknownColor> {
+        if Self::XCOLOR_BASE.contains(&name) {
+            return Ok(Self::predefined(name).unwrap_or_else(|| Color::Named(name.to_string())));
+        }
+
+        if preamble.contains("dvipsnames") {
+            if let Some(color) = Self::predefined(name) {
+                return Ok(color);
+            }
+        }
+
+        if preamble.contains("svgnames") || preamble.contains("x11names") {
+            return Ok(Color::Named(name.to_string()));
+        }
+
+        Err(UnknownColor(name.to_string()))
+    }
+
+    /// The variant of [`Color::NAMED`] called `name`.
+    fn predefined(name: &str) -> Option<Self> {
+        Self::NAMED
+            .iter()
+            .find(|color| color.name() == Some(name))
+            .cloned()
+    }
+
+    /// Build a [`Color::Rgb`] from a `0xRRGGBB` value. Bits above the lower 24 are ignored.
+    pub fn from_hex(hex: u32) -> Self {
+        Color::Rgb((hex >> 16) as u8, (hex >> 8) as u8, hex as u8)
+    }
+
+    /// Name of the colour as understood by `xcolor`, `None` for [`Color::Rgb`].
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Color::Apricot => Some("Apricot"),
+            Color::Aquamarine => Some("Aquamarine"),
+            Color::Bittersweet => Some("Bittersweet"),
+            Color::Black => Some("black"),
+            Color::Blue => Some("Blue"),
+            Color::BlueGreen => Some("BlueGreen"),
+            Color::BlueViolet => Some("BlueViolet"),
+            Color::BrickRed => Some("BrickRed"),
+            Color::Brown => Some("Brown"),
+            Color::BurntOrange => Some("BurntOrange"),
+            Color::CadetBlue => Some("CadetBlue"),
+            Color::CarnationPink => Some("CarnationPink"),
+            Color::Cerulean => Some("Cerulean"),
+            Color::CornflowerBlue => Some("CornflowerBlue"),
+            Color::Cyan => Some("Cyan"),
+            Color::Dandelion => Some("Dandelion"),
+            Color::DarkOrchid => Some("DarkOrchid"),
+            Color::Emerald => Some("Emerald"),
+            Color::ForestGreen => Some("ForestGreen"),
+            Color::Fuchsia => Some("Fuchsia"),
+            Color::Goldenrod => Some("Goldenrod"),
+            Color::Gray => Some("Gray"),
+            Color::Green => Some("Green"),
+            Color::GreenYellow => Some("GreenYellow"),
+            Color::JungleGreen => Some("JungleGreen"),
+            Color::Lavender => Some("Lavender"),
+            Color::LimeGreen => Some("LimeGreen"),
+            Color::Magenta => Some("Magenta"),
+            Color::Mahogany => Some("Mahogany"),
+            Color::Maroon => Some("Maroon"),
+            Color::Melon => Some("Melon"),
+            Color::MidnightBlue => Some("MidnightBlue"),
+            Color::Mulberry => Some("Mulberry"),
+            Color::NavyBlue => Some("NavyBlue"),
+            Color::OliveGreen => Some("OliveGreen"),
+            Color::Orange => Some("Orange"),
+            Color::OrangeRed => Some("OrangeRed"),
+            Color::Orchid => Some("Orchid"),
+            Color::Peach => Some("Peach"),
+            Color::Periwinkle => Some("Periwinkle"),
+            Color::PineGreen => Some("PineGreen"),
+            Color::Plum => Some("Plum"),
+            Color::ProcessBlue => Some("ProcessBlue"),
+            Color::Purple => Some("Purple"),
+            Color::RawSienna => Some("RawSienna"),
+            Color::Red => Some("Red"),
+            Color::RedOrange => Some("RedOrange"),
+            Color::RedViolet => Some("RedViolet"),
+            Color::Rhodamine => Some("Rhodamine"),
+            Color::RoyalBlue => Some("RoyalBlue"),
+            Color::RoyalPurple => Some("RoyalPurple"),
+            Color::RubineRed => Some("RubineRed"),
+            Color::Salmon => Some("Salmon"),
+            Color::SeaGreen => Some("SeaGreen"),
+            Color::Sepia => Some("Sepia"),
+            Color::SkyBlue => Some("SkyBlue"),
+            Color::SpringGreen => Some("SpringGreen"),
+            Color::Tan => Some("Tan"),
+            Color::TealBlue => Some("TealBlue"),
+            Color::Thistle => Some("Thistle"),
+            Color::Turquoise => Some("Turquoise"),
+            Color::Violet => Some("Violet"),
+            Color::VioletRed => Some("VioletRed"),
+            Color::White => Some("white"),
+            Color::WildStrawberry => Some("WildStrawberry"),
+            Color::Yellow => Some("Yellow"),
+            Color::YellowGreen => Some("YellowGreen"),
+            Color::YellowOrange => Some("YellowOrange"),
+            Color::Named(name) => Some(name),
+            Color::Rgb(..) => None,
+        }
+    }
 }
 
 impl TexString for Color {
     fn to_tex(&self) -> String {
         match self {
-            Color::Black => r#"\color{black}"#.to_string(),
-            Color::White => r#"\color{white}"#.to_string(),
+            Color::Rgb(r, g, b) => format!(r#"\color[HTML]{{{:02x}{:02x}{:02x}}}"#, r, g, b),
+            named => format!(r#"\color{{{}}}"#, named.name().unwrap_or_default()),
         }
     }
 }
 
-impl Default for Color {
-    fn default() -> Self {
-        Self::Black
+/// Returned by [`Color::named`] for a name the preamble does not define.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, thiserror::Error)]
+#[error("unknown xcolor colour `{0}`")]
+pub struct UnknownColor(pub String);
+
+/// Parses `#rrggbb` as [`Color::Rgb`], the names of the predefined variants such as `black` or
+/// `Mahogany`, and the base `xcolor` names as [`Color::Named`].
+///
+/// Unlike [`Color::named`] the preamble is not consulted, `dvipsnames` colours still need the
+/// option to be loaded.
+impl std::str::FromStr for Color {
+    type Err = UnknownColor;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(color) = Self::predefined(s) {
+            return Ok(color);
+        }
+
+        if Self::XCOLOR_BASE.contains(&s) {
+            return Ok(Color::Named(s.to_string()));
+        }
+
+        match s.strip_prefix('#') {
+            Some(hex) if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+                Ok(Color::from_hex(u32::from_str_radix(hex, 16).unwrap()))
+            }
+            _ => Err(UnknownColor(s.to_string())),
+        }
     }
 }
 
-/*
 impl<T: TexString> Usepackage<T> {
     pub fn new(main: T) -> Self {
         Self {
@@ -96,8 +474,9 @@ impl<T: TexString> Usepackage<T> {
 impl<T: TexString> TexString for Usepackage<T> {
     fn to_tex(&self) -> String {
         let mut out = String::from(r#"\usepackage"#);
-        for tok in &self.arbitrary {
-            out.push_str(&format!("[{}]", tok.to_tex()));
+        if !self.arbitrary.is_empty() {
+            let options = self.arbitrary.iter().map(T::to_tex).collect::<Vec<_>>();
+            out.push_str(&format!("[{}]", options.join(",")));
         }
         out.push_str(&format!("{{{}}}", self.main.to_tex()));
         out
@@ -113,7 +492,231 @@ impl<T: TexString> TexString for Vec<Usepackage<T>> {
         out
     }
 }
-*/
+
+/// Kind of problem found by [`validate`].
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ValidationIssueKind {
+    /// `{` without a matching `}`.
+    UnclosedBrace,
+    /// `}` without a matching `{`.
+    UnopenedBrace,
+    /// `\left` without a matching `\right`.
+    UnclosedLeft,
+    /// `\right` without a matching `\left`.
+    UnopenedRight,
+    /// `\begin{name}` without a matching `\end{name}`.
+    UnclosedEnvironment(String),
+    /// `\end{name}` without a matching `\begin{name}`.
+    UnopenedEnvironment(String),
+}
+
+/// Problem found by [`validate`], located in the validated string.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ValidationIssue {
+    pub kind: ValidationIssueKind,
+    /// Byte offset.
+    pub offset: usize,
+    /// 1-based line.
+    pub line: usize,
+    /// 1-based column, in characters.
+    pub column: usize,
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let what = match &self.kind {
+            ValidationIssueKind::UnclosedBrace => r#"unclosed `{`"#.to_string(),
+            ValidationIssueKind::UnopenedBrace => r#"unmatched `}`"#.to_string(),
+            ValidationIssueKind::UnclosedLeft => r#"`\left` without `\right`"#.to_string(),
+            ValidationIssueKind::UnopenedRight => r#"`\right` without `\left`"#.to_string(),
+            ValidationIssueKind::UnclosedEnvironment(name) => {
+                format!(r#"`\begin{{{}}}` without `\end{{{}}}`"#, name, name)
+            }
+            ValidationIssueKind::UnopenedEnvironment(name) => {
+                format!(r#"`\end{{{}}}` without `\begin{{{}}}`"#, name, name)
+            }
+        };
+
+        write!(f, "{}:{}: {}", self.line, self.column, what)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Opener {
+    Brace,
+    Left,
+    Environment(String),
+}
+
+/// Check `tex` for unbalanced `{}`, `\left`/`\right` and `\begin`/`\end` pairs without invoking
+/// (La)TeX. Comments and escaped braces (`\{`, `\}`) are ignored.
+pub fn validate(tex: &str) -> Result<(), Vec<ValidationIssue>> {
+    let mut issues = Vec::new();
+    let mut stack: Vec<(Opener, ValidationIssue)> = Vec::new();
+
+    let (mut line, mut column) = (1, 0);
+    let mut chars = tex.char_indices().peekable();
+
+    let issue = |kind, offset, line, column| ValidationIssue {
+        kind,
+        offset,
+        line,
+        column,
+    };
+
+    while let Some((offset, c)) = chars.next() {
+        column += 1;
+
+        match c {
+            '\n' => {
+                line += 1;
+                column = 0;
+            }
+            '%' => while chars.next_if(|&(_, c)| c != '\n').is_some() {},
+            '{' => stack.push((
+                Opener::Brace,
+                issue(ValidationIssueKind::UnclosedBrace, offset, line, column),
+            )),
+            '}' => match stack.last() {
+                Some((Opener::Brace, _)) => {
+                    stack.pop();
+                }
+                _ => issues.push(issue(
+                    ValidationIssueKind::UnopenedBrace,
+                    offset,
+                    line,
+                    column,
+                )),
+            },
+            '\\' => {
+                let start_column = column;
+                let mut name = String::new();
+                while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_alphabetic()) {
+                    name.push(c);
+                    column += 1;
+                }
+
+                // Control symbols such as `\{` or `\%` are a single escaped character.
+                if name.is_empty() {
+                    if chars.next_if(|&(_, c)| c != '\n').is_some() {
+                        column += 1;
+                    }
+                    continue;
+                }
+
+                let at = |kind| issue(kind, offset, line, start_column);
+
+                match name.as_str() {
+                    "left" => stack.push((Opener::Left, at(ValidationIssueKind::UnclosedLeft))),
+                    "right" => match stack.last() {
+                        Some((Opener::Left, _)) => {
+                            stack.pop();
+                        }
+                        _ => issues.push(at(ValidationIssueKind::UnopenedRight)),
+                    },
+                    "begin" | "end" => {
+                        if chars.next_if(|&(_, c)| c == '{').is_none() {
+                            continue;
+                        }
+                        column += 1;
+
+                        let mut env = String::new();
+                        while let Some((_, c)) = chars.next_if(|&(_, c)| c != '}' && c != '\n') {
+                            env.push(c);
+                            column += 1;
+                        }
+                        if chars.next_if(|&(_, c)| c == '}').is_some() {
+                            column += 1;
+                        }
+
+                        if name == "begin" {
+                            let kind = ValidationIssueKind::UnclosedEnvironment(env.clone());
+                            stack.push((Opener::Environment(env), at(kind)));
+                        } else {
+                            match stack.last() {
+                                Some((Opener::Environment(open), _)) if *open == env => {
+                                    stack.pop();
+                                }
+                                _ => issues.push(at(ValidationIssueKind::UnopenedEnvironment(env))),
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    issues.extend(stack.into_iter().map(|(_, issue)| issue));
+    issues.sort_by_key(|issue| issue.offset);
+
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(issues)
+    }
+}
+
+/// Control words that run programs or touch files, or that can be used to disguise those, such
+/// as `\catcode` and `\csname`. Matched as whole control words, so `\includegraphics` is not
+/// caught by `include`.
+pub const UNSAFE_PRIMITIVES: &[&str] = &[
+    "write",
+    "immediate",
+    "openin",
+    "openout",
+    "read",
+    "readline",
+    "input",
+    "include",
+    "InputIfFileExists",
+    "verbatiminput",
+    "lstinputlisting",
+    "ShellEscape",
+    "directlua",
+    "catcode",
+    "csname",
+    "scantokens",
+];
+
+/// The [`UNSAFE_PRIMITIVES`] used in `tex`, with their backslash, in order of first use. `^^`
+/// character notation, which could spell any of them, is reported as `^^`.
+pub fn unsafe_primitives(tex: &str) -> Vec<String> {
+    let mut found: Vec<String> = Vec::new();
+    let mut report = |name: String| {
+        if !found.contains(&name) {
+            found.push(name);
+        }
+    };
+
+    let mut chars = tex.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '\\' => {
+                let start = offset + 1;
+                let mut end = start;
+                while let Some((i, c)) = chars.next_if(|&(_, c)| c.is_ascii_alphabetic()) {
+                    end = i + c.len_utf8();
+                }
+
+                // `\\` is a line break, the backslash after it does not start a control word.
+                if end == start {
+                    chars.next_if(|&(_, c)| c == '\\');
+                }
+
+                let name = &tex[start..end];
+                if UNSAFE_PRIMITIVES.contains(&name) {
+                    report(format!(r#"\{}"#, name));
+                }
+            }
+            '^' if chars.next_if(|&(_, c)| c == '^').is_some() => report("^^".to_string()),
+            _ => {}
+        }
+    }
+
+    found
+}
 
 /// Represents a (La)TeX MathMode token string.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -122,6 +725,27 @@ pub enum MathMode<T: TexString> {
     Inline(Vec<T>),
     /// Displayed math mode i.e. `\[ tok... \]`
     Displayed(Vec<T>),
+    /// Equation environment i.e. `\begin{equation} tok... \end{equation}`, or `equation*` when
+    /// `number` is `false`.
+    Equation { number: bool, tokens: Vec<T> },
+    /// Multi-line alignment i.e. `\begin{align*} tok... \end{align*}`, lines are separated by
+    /// `\\` and aligned on `&` within the tokens themselves.
+    Align(Vec<T>),
+    /// Lines aligned within displayed math i.e.
+    /// `\[ \begin{aligned} tok \\ tok... \end{aligned} \]`, each token is a line.
+    Aligned(Vec<T>),
+    /// Case distinction within displayed math i.e.
+    /// `\[ \begin{cases} tok \\ tok... \end{cases} \]`, each token is a case.
+    Cases(Vec<T>),
+}
+
+/// The [`TexString::to_tex`] of each token in `tokens`, joined by `\\`.
+fn join_lines<T: TexString>(tokens: &[T]) -> String {
+    tokens
+        .iter()
+        .map(TexString::to_tex)
+        .collect::<Vec<_>>()
+        .join(r#" \\ "#)
 }
 
 impl<T: TexString> TexString for MathMode<T> {
@@ -145,6 +769,38 @@ impl<T: TexString> TexString for MathMode<T> {
 
                 format!(r#"\[ {} \]"#, inner)
             }
+            MathMode::Equation { number, tokens } => {
+                let mut inner = String::new();
+
+                for tok in tokens.iter() {
+                    inner.push_str(&tok.to_tex());
+                }
+
+                let env = if *number { "equation" } else { "equation*" };
+
+                format!(r#"\begin{{{}}} {} \end{{{}}}"#, env, inner, env)
+            }
+            MathMode::Align(align) => {
+                let mut inner = String::new();
+
+                for tok in align.iter() {
+                    inner.push_str(&tok.to_tex());
+                }
+
+                format!(r#"\begin{{align*}} {} \end{{align*}}"#, inner)
+            }
+            MathMode::Aligned(lines) => {
+                format!(
+                    r#"\[ \begin{{aligned}} {} \end{{aligned}} \]"#,
+                    join_lines(lines)
+                )
+            }
+            MathMode::Cases(cases) => {
+                format!(
+                    r#"\[ \begin{{cases}} {} \end{{cases}} \]"#,
+                    join_lines(cases)
+                )
+            }
         }
     }
 }
@@ -163,5 +819,209 @@ mod tests {
             assert_eq!(mm1.to_tex(), r#"\[ 1+1 \]"#);
             assert_eq!(mm2.to_tex(), r#"\( \sqrt{2}i^2 \)"#);
         }
+
+        #[test]
+        fn equation() {
+            let numbered = MathMode::Equation {
+                number: true,
+                tokens: vec!["E=mc^2"],
+            };
+            let unnumbered = MathMode::Equation {
+                number: false,
+                tokens: vec!["E=mc^2"],
+            };
+
+            assert_eq!(
+                numbered.to_tex(),
+                r#"\begin{equation} E=mc^2 \end{equation}"#
+            );
+            assert_eq!(
+                unnumbered.to_tex(),
+                r#"\begin{equation*} E=mc^2 \end{equation*}"#
+            );
+        }
+
+        #[test]
+        fn aligned_cases() {
+            let aligned = MathMode::Aligned(vec!["a &= b", "&= c"]);
+            let cases = MathMode::Cases(vec![r#"1 & x > 0"#, r#"0 & \text{otherwise}"#]);
+
+            assert_eq!(
+                aligned.to_tex(),
+                r#"\[ \begin{aligned} a &= b \\ &= c \end{aligned} \]"#
+            );
+            assert_eq!(
+                cases.to_tex(),
+                r#"\[ \begin{cases} 1 & x > 0 \\ 0 & \text{otherwise} \end{cases} \]"#
+            );
+            assert_eq!(
+                MathMode::Aligned(vec!["x"]).to_tex(),
+                r#"\[ \begin{aligned} x \end{aligned} \]"#
+            );
+        }
+    }
+
+    mod usepackage {
+        use crate::tex::{TexString, Usepackage};
+
+        #[test]
+        fn to_tex() {
+            let bare = Usepackage::new("amsmath");
+            let opts = Usepackage::new_with_arbitrary("geometry", vec!["margin=1in", "a4paper"]);
+
+            assert_eq!(bare.to_tex(), r#"\usepackage{amsmath}"#);
+            assert_eq!(
+                opts.to_tex(),
+                r#"\usepackage[margin=1in,a4paper]{geometry}"#
+            );
+            assert_eq!(vec![bare].to_tex(), "\\usepackage{amsmath}\n".to_string());
+        }
+    }
+
+    mod validate {
+        use crate::tex::{validate, ValidationIssue, ValidationIssueKind};
+
+        #[test]
+        fn balanced() {
+            assert_eq!(validate(r#"\frac{1}{2} \left( x \right) \{ \}"#), Ok(()));
+            assert_eq!(validate("\\begin{cases} a \\end{cases} % {"), Ok(()));
+        }
+
+        #[test]
+        fn unbalanced() {
+            let issues = validate("\\frac{1}{2\n\\left( x }").unwrap_err();
+
+            assert_eq!(
+                issues,
+                vec![
+                    ValidationIssue {
+                        kind: ValidationIssueKind::UnclosedBrace,
+                        offset: 8,
+                        line: 1,
+                        column: 9,
+                    },
+                    ValidationIssue {
+                        kind: ValidationIssueKind::UnclosedLeft,
+                        offset: 11,
+                        line: 2,
+                        column: 1,
+                    },
+                    ValidationIssue {
+                        kind: ValidationIssueKind::UnopenedBrace,
+                        offset: 20,
+                        line: 2,
+                        column: 10,
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn environments() {
+            let issues = validate(r#"\begin{aligned} x \end{cases}"#).unwrap_err();
+            let kinds = issues.into_iter().map(|i| i.kind).collect::<Vec<_>>();
+
+            assert_eq!(
+                kinds,
+                vec![
+                    ValidationIssueKind::UnclosedEnvironment("aligned".to_string()),
+                    ValidationIssueKind::UnopenedEnvironment("cases".to_string()),
+                ]
+            );
+        }
+    }
+
+    mod unsafe_primitives {
+        use crate::tex::unsafe_primitives;
+
+        #[test]
+        fn detect() {
+            assert!(unsafe_primitives(r#"\includegraphics{a.png} \inputenc x^2"#).is_empty());
+            assert_eq!(
+                unsafe_primitives(r#"\immediate\write18{rm -rf ~} \input{/etc/passwd}\write"#),
+                vec![r#"\immediate"#, r#"\write"#, r#"\input"#]
+            );
+            assert_eq!(unsafe_primitives(r#"\^^69nput"#), vec!["^^"]);
+            assert!(unsafe_primitives(r#"a \\input"#).is_empty());
+            assert_eq!(unsafe_primitives(r#"a \\\input"#), vec![r#"\input"#]);
+        }
+    }
+
+    mod color {
+        use crate::tex::{Color, TexString, UnknownColor};
+
+        #[test]
+        fn to_tex() {
+            assert_eq!(Color::Black.to_tex(), r#"\color{black}"#);
+            assert_eq!(Color::Red.to_tex(), r#"\color{Red}"#);
+            assert_eq!(Color::Named("teal".to_string()).to_tex(), r#"\color{teal}"#);
+            assert_eq!(Color::Rgb(255, 0, 0).to_tex(), r#"\color[HTML]{ff0000}"#);
+        }
+
+        #[test]
+        fn from_hex() {
+            assert_eq!(Color::from_hex(0xFF8800), Color::Rgb(0xFF, 0x88, 0x00));
+            assert_eq!(
+                Color::from_hex(0xFF8800).to_tex(),
+                r#"\color[HTML]{ff8800}"#
+            );
+            assert_eq!(
+                Color::from_hex(0x000000).to_tex(),
+                r#"\color[HTML]{000000}"#
+            );
+            assert_eq!(
+                Color::from_hex(0x0a0b0c).to_tex(),
+                r#"\color[HTML]{0a0b0c}"#
+            );
+        }
+
+        #[test]
+        fn named() {
+            assert_eq!(Color::NAMED.len(), 68);
+        }
+
+        #[test]
+        fn named_validation() {
+            let dvipsnames = r#"\usepackage[dvipsnames]{xcolor}"#;
+
+            assert_eq!(
+                Color::named("teal", ""),
+                Ok(Color::Named("teal".to_string()))
+            );
+            assert_eq!(Color::named("black", ""), Ok(Color::Black));
+            assert_eq!(Color::named("white", ""), "white".parse());
+            assert_eq!(Color::named("Mahogany", dvipsnames), Ok(Color::Mahogany));
+            assert_eq!(
+                Color::named("Mahogany", ""),
+                Err(UnknownColor("Mahogany".to_string()))
+            );
+            assert!(Color::named("notacolour", dvipsnames).is_err());
+            assert!(Color::named("LightSlateGray", r#"\usepackage[svgnames]{xcolor}"#).is_ok());
+        }
+
+        #[test]
+        fn from_str() {
+            assert_eq!("black".parse(), Ok(Color::Black));
+            assert_eq!("white".parse(), Ok(Color::White));
+            assert_eq!("Mahogany".parse(), Ok(Color::Mahogany));
+            assert_eq!("teal".parse(), Ok(Color::Named("teal".to_string())));
+            assert_eq!("#FF8800".parse(), Ok(Color::Rgb(0xFF, 0x88, 0x00)));
+            assert_eq!("#0a0b0c".parse(), Ok(Color::Rgb(0x0a, 0x0b, 0x0c)));
+
+            for invalid in [
+                "",
+                "Black",
+                "notacolour",
+                "#fff",
+                "#+12345",
+                "#gg0000",
+                "ff8800",
+            ] {
+                assert_eq!(
+                    invalid.parse::<Color>(),
+                    Err(UnknownColor(invalid.to_string()))
+                );
+            }
+        }
     }
 }
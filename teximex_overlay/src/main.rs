@@ -1,21 +1,21 @@
 use std::sync::{Arc, Mutex};
-use std::{borrow::Cow, sync::mpsc};
+use std::time::{Duration, Instant};
+use std::sync::mpsc;
 
-use arboard::{Clipboard, ImageData};
-use eframe::egui::{Id, ScrollArea, Sense, RichText, Button, Style};
+use eframe::egui::{ColorImage, Id, ScrollArea, Sense, RichText, Button, Style};
 use eframe::emath::Align2;
 use eframe::epaint::{vec2, Color32, FontId, Rgba, Stroke};
 use eframe::IconData;
 use eframe::{egui, epaint::Vec2};
 use egui_extras::RetainedImage;
-use image::EncodableLayout;
 use mktemp::Temp;
+use teximex::arboard::Clipboard;
 use teximex::render::native::LogRecord;
 use teximex::{
-    document::{Document, DocumentBuilder, DocumentMathMode, DocumentOptions},
+    document::{BuildError, Document, DocumentBuilder, DocumentMathMode, DocumentOptions},
     render::{
         native::{RenderInstanceNative},
-        RenderBackend, RenderInstance, RenderOptions,
+        CancelToken, RenderError, RenderInstance, RenderOptions, RenderStage,
     },
     tex::{Color, MathMode, TexString},
 };
@@ -51,18 +51,16 @@ enum ContentType {
 enum Packet {
     Image(ImagePacket),
     NoImage(Logs),
+    Stage(RenderStage),
 }
 
-type ImagePacket = (
-    Vec<u8>,
-    (image::ImageBuffer<image::Rgba<u8>, Vec<u8>>, (u32, u32)),
-    Logs,
-);
+type ImagePacket = (image::RgbaImage, Logs);
 
 type Logs = Vec<LogRecord>;
 
-type ImageSender = mpsc::Sender<Packet>;
-type ImageReceiver = mpsc::Receiver<Packet>;
+/// Packets are tagged with the number of the render that sent them, see [`TeximexApp::renders`].
+type ImageSender = mpsc::Sender<(u64, Packet)>;
+type ImageReceiver = mpsc::Receiver<(u64, Packet)>;
 
 struct TeximexApp {
     input: String,
@@ -79,6 +77,11 @@ struct TeximexApp {
     logs: Vec<LogRecord>,
     img: Option<RetainedImage>,
     additional_preamble: String,
+    render_on_type: RenderOnType,
+    cancel: CancelToken,
+    stage: Option<RenderStage>,
+    /// Number of the latest render, packets of earlier ones are dropped.
+    renders: u64,
 }
 
 impl TeximexApp {
@@ -98,24 +101,28 @@ impl TeximexApp {
             logs: Vec::new(),
             img: None,
             additional_preamble: String::new(),
+            render_on_type: RenderOnType::default(),
+            cancel: CancelToken::new(),
+            stage: None,
+            renders: 0,
         }
     }
 }
 
 impl TeximexApp {
-    fn compile_document(&self) -> Document<String> {
+    fn compile_document(&self) -> Result<Document<String>, BuildError> {
         match &self.content_type {
             ContentType::MathMode => {
                 let doc = Document::builder(&*self.input);
                 let mut math_doc = doc.mathmode(DocumentMathMode::Displayed);
-                math_doc.color(self.color);
+                math_doc.color(self.color.clone());
                 math_doc.add_preamble(self.additional_preamble.clone());
 
                 math_doc.build()
             }
             ContentType::Raw => {
                 let mut doc = Document::builder(&*self.input);
-                doc.color(self.color);
+                doc.color(self.color.clone());
                 doc.add_preamble(self.additional_preamble.clone());
 
                 doc.build()
@@ -124,37 +131,105 @@ impl TeximexApp {
     }
 
     fn document_to_string(&self) -> String {
-        let doc = self.compile_document();
-        doc.to_tex()
+        match self.compile_document() {
+            Ok(doc) => doc.to_tex_indent(),
+            Err(err) => format!("% {}", err),
+        }
     }
 
     fn render_img(&mut self) {
-        let doc = self.compile_document();
-
-        println!("{}", doc.to_tex());
-
-        let ri = RenderInstance::<String>::new_with_options(RenderOptions::new(
-            Some(self.scale),
-            Some(self.margin),
-        ))
-        .load(doc);
-
-        let mut rin = RenderInstanceNative::new(&self.tmp.as_path(), ri);
+        let doc = match self.compile_document() {
+            Ok(doc) => doc,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        };
+
+        // A superseded render may still be compiling, its own job name keeps it from
+        // overwriting this one's files in the shared directory.
+        self.renders += 1;
+        let mut options = RenderOptions::new(Some(self.scale), Some(self.margin));
+        options.set_job_name(Some(format!("render{}", self.renders)));
+
+        let ri = RenderInstance::<String>::new_with_options(options).load(doc);
+
+        // A newer render supersedes whatever is still in flight.
+        self.cancel.cancel();
+        self.cancel = CancelToken::new();
+
+        let render = self.renders;
+        let tx_stage = self.render_tx.clone();
+        let mut rin = RenderInstanceNative::new(&self.tmp.as_path(), ri)
+            .with_cleanup_on_drop()
+            .with_cancel_token(self.cancel.clone())
+            .on_progress(move |stage| {
+                let _ = tx_stage.send((render, Packet::Stage(stage)));
+            });
 
         let tx_j = self.render_tx.clone();
-        std::thread::spawn(move || {
-            if let Ok(data) = rin.render() {
-                let img = image::load_from_memory(&data).unwrap().into_rgba8();
-                let (w, h) = img.dimensions();
-
-                tx_j.send(Packet::Image((data, (img, (w, h)), rin.logs)))
+        std::thread::spawn(move || match rin.render_image() {
+            Ok(image) => {
+                tx_j.send((render, Packet::Image((image, std::mem::take(&mut rin.logs)))))
+                    .unwrap();
+            }
+            Err(RenderError::Cancelled) => {}
+            Err(err) => {
+                eprintln!("{}", err);
+                tx_j.send((render, Packet::NoImage(std::mem::take(&mut rin.logs))))
                     .unwrap();
-            } else {
-                tx_j.send(Packet::NoImage(rin.logs)).unwrap();
             }
         });
 
         self.render_ready = false;
+        self.stage = None;
+    }
+}
+
+/// Decides when typing triggers a render: once the input has been quiet for `debounce_ms`, so a
+/// burst of keystrokes fires a single render.
+struct RenderOnType {
+    enabled: bool,
+    debounce_ms: u64,
+    last_keystroke: Option<Instant>,
+}
+
+impl Default for RenderOnType {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            debounce_ms: 800,
+            last_keystroke: None,
+        }
+    }
+}
+
+impl RenderOnType {
+    /// Note that the input changed at `now`. Ignored while disabled.
+    fn edited(&mut self, now: Instant) {
+        if self.enabled {
+            self.last_keystroke = Some(now);
+        }
+    }
+
+    /// Whether to render at `now`, `true` once per burst of edits.
+    fn due(&mut self, now: Instant) -> bool {
+        match self.last_keystroke {
+            Some(t) if now.duration_since(t) >= self.debounce() => {
+                self.last_keystroke = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// An edit is waiting for the input to go quiet.
+    fn pending(&self) -> bool {
+        self.last_keystroke.is_some()
+    }
+
+    fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms)
     }
 }
 
@@ -183,7 +258,7 @@ impl eframe::App for TeximexApp {
 
             ui.label("Enter (La)TeX here");
 
-            ui.add(
+            let editor = ui.add(
                 egui::TextEdit::multiline(&mut self.input)
                     .desired_width(f32::INFINITY)
                     .desired_rows(usize::MAX)
@@ -194,6 +269,23 @@ impl eframe::App for TeximexApp {
                     .lock_focus(true),
             );
 
+            if let Err(issues) = teximex::tex::validate(&self.input) {
+                for issue in issues {
+                    ui.colored_label(Color32::LIGHT_RED, issue.to_string());
+                }
+            }
+
+            let now = Instant::now();
+            if editor.changed() {
+                self.render_on_type.edited(now);
+            }
+
+            if self.render_on_type.due(now) {
+                self.render_img();
+            } else if self.render_on_type.pending() {
+                ctx.request_repaint_after(self.render_on_type.debounce());
+            }
+
              ui.horizontal(|ui| {
                 if ui.button(RichText::new("RENDER").monospace()).clicked() {
                     self.render_img();
@@ -215,8 +307,9 @@ impl eframe::App for TeximexApp {
                 egui::ComboBox::from_label("Text color")
                     .selected_text(format!("{:?}", self.color))
                     .show_ui(ui, |ui| {
-                        ui.selectable_value(&mut self.color, Color::Black, "Black");
-                        ui.selectable_value(&mut self.color, Color::White, "White");
+                        for color in Color::NAMED {
+                            ui.selectable_value(&mut self.color, color.clone(), format!("{:?}", color));
+                        }
                     });
 
                 egui::ComboBox::from_label("Input type")
@@ -236,6 +329,10 @@ impl eframe::App for TeximexApp {
                     ui.label("Rendering / post-TeX related options.");
                     ui.add(egui::Slider::new(&mut self.scale, 1.0..=10.0).text("scale"));
                     ui.add(egui::Slider::new(&mut self.margin, 0.0..=128.0).text("margin"));
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut self.render_on_type.enabled, "render on type");
+                        ui.add(egui::DragValue::new(&mut self.render_on_type.debounce_ms).suffix(" ms"));
+                    });
                 })
             });
 
@@ -245,6 +342,11 @@ impl eframe::App for TeximexApp {
                 }
             });
 
+            let warnings = self.logs.iter().filter(|record| record.is_warning()).count();
+            if warnings > 0 {
+                ui.colored_label(Color32::YELLOW, format!("{} warning(s), see log", warnings));
+            }
+
             ui.collapsing("preamble", |ui| {
                 ui.label(r#"Enter (optional)[additional] preamble to be inserted before `\begin{document}`"#);
                 ui.code_editor(&mut self.additional_preamble);
@@ -256,54 +358,88 @@ impl eframe::App for TeximexApp {
                     code_view_ui(ui, {
                         &self.logs.iter().enumerate().map(|pair| {
                         if pair.0 == self.logs.len() - 1 {
-                            format!("{:#?}", pair.1)
+                            format!("{}", pair.1)
                         } else {
-                            format!("{:#?}\n", pair.1)
+                            format!("{}\n", pair.1)
                         }
                     }).collect::<String>()
                     });
                 });
             });
 
-            if let Ok(data) = self.render_rx.try_recv() {
+            if let Ok((render, data)) = self.render_rx.try_recv() {
                 match data {
+                    // A superseded render may still finish, fail or report stages after the
+                    // newer one started, none of which concern the current render.
+                    _ if render != self.renders => {}
                     Packet::Image(data) => {
-                        let img_data = ImageData {
-                            width: (data.1).1 .0 as usize,
-                            height: (data.1).1 .1 as usize,
-                            bytes: Cow::Borrowed(data.1 .0.as_bytes()),
-                        };
-
-                        match RetainedImage::from_image_bytes("out", data.0.as_bytes()) {
-                            Ok(image) => {
-                                self.img = Some(image);
-                            }
-                            Err(_) => {}
-                        }
-
-                        dbg!(self.img.is_none());
+                        let size = [data.0.width() as usize, data.0.height() as usize];
+                        let pixels = ColorImage::from_rgba_unmultiplied(size, data.0.as_raw());
+                        self.img = Some(RetainedImage::from_color_image("out", pixels));
 
-                        self.clipboard.set_image(img_data).unwrap();
+                        if let Err(err) = teximex::copy_rgba_image_to(&mut self.clipboard, &data.0) {
+                            eprintln!("{}", err);
+                        }
 
-                        self.logs = data.2;
+                        self.logs = data.1;
 
                         self.render_ready = true;
                     }
                     Packet::NoImage(data) => {
-
-                        println!("NO IMAGI");
-
                         self.logs = data;
                         self.render_ready = true;
                     }
+                    Packet::Stage(stage) => {
+                        self.stage = Some(stage);
+                    }
                 }
-            } else {
-                if !self.render_ready {
+            }
+
+            if !self.render_ready {
+                ui.horizontal(|ui| {
                     ui.add(egui::Spinner::new());
-                }
+                    if let Some(stage) = self.stage {
+                        ui.label(format!("{:?}", stage));
+                    }
+                });
             }
         });
 
         frame.set_window_size(ctx.used_size());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::RenderOnType;
+
+    /// Frames every 16ms for two seconds with a keystroke every 96ms during the first half
+    /// second, counting the renders triggered.
+    fn renders(trigger: &mut RenderOnType) -> usize {
+        let start = Instant::now();
+
+        (0..125u64)
+            .filter(|frame| {
+                let now = start + Duration::from_millis(frame * 16);
+                if frame * 16 < 500 && frame % 6 == 0 {
+                    trigger.edited(now);
+                }
+                trigger.due(now)
+            })
+            .count()
+    }
+
+    #[test]
+    fn render_on_type() {
+        let mut trigger = RenderOnType {
+            enabled: true,
+            ..Default::default()
+        };
+        assert_eq!(renders(&mut trigger), 1);
+        assert!(!trigger.pending());
+
+        assert_eq!(renders(&mut RenderOnType::default()), 0);
+    }
+}